@@ -0,0 +1,165 @@
+use serde::Serialize;
+use crate::blockchain::Blockchain;
+use crate::types::{
+    address::Address,
+    hash::{H256, Hashable},
+    mempool::{Mempool, TransactionStatus},
+    transaction::SignedTransaction,
+};
+
+// Number of confirmed transactions shown on an account page
+const RECENT_TRANSACTIONS: usize = 20;
+
+#[derive(Serialize)]
+pub struct BlockInfo {
+    hash: String,
+    height: u64,
+    confirmations: u64,     // 0 if the block is not in the longest chain
+    parent: String,
+    nonce: u32,
+    difficulty: String,
+    timestamp: u128,
+    merkle_root: String,
+    state_root: String,
+    size: usize,
+    coinbase: CoinbaseInfo,
+    transactions: Vec<TransactionInfo>,
+}
+
+#[derive(Serialize)]
+pub struct CoinbaseInfo {
+    receiver: String,
+    value: u128,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TransactionState {
+    Confirmed,    // in a block of the longest chain
+    Ready,        // pending in the mempool and minable on the current tip
+    Future,       // pending in the mempool behind a missing nonce or balance
+}
+
+#[derive(Serialize)]
+pub struct TransactionInfo {
+    hash: String,
+    sender: String,
+    receiver: String,
+    account_nonce: u128,
+    value: u128,
+    fee: u128,
+    status: TransactionState,
+    block: Option<String>,
+    height: Option<u64>,
+    position: Option<usize>,
+    confirmations: u64,
+}
+
+#[derive(Serialize)]
+pub struct AccountInfo {
+    address: String,
+    nonce: u128,
+    balance: u128,
+    pending: Vec<TransactionInfo>,    // in nonce order
+    recent: Vec<TransactionInfo>,     // confirmed, newest first
+}
+
+/// Describe a block, whether or not it is in the longest chain
+pub fn block_info(blockchain: &Blockchain, block_hash: &H256) -> Option<BlockInfo> {
+    let block = blockchain.get_block(block_hash).ok()?;
+    let height = blockchain.get_height(block_hash).ok()?;
+    let confirmations = blockchain.confirmations(block_hash);
+    let transactions = block.content.transactions.iter().enumerate()
+        .map(|(position, txn)| {
+            let mut info = transaction_fields(txn, TransactionState::Confirmed);
+            info.block = Some(block_hash.to_string());
+            info.height = Some(height);
+            info.position = Some(position);
+            info.confirmations = confirmations;
+            info
+        })
+        .collect();
+
+    Some(BlockInfo {
+        hash: block_hash.to_string(),
+        height,
+        confirmations,
+        parent: block.header.parent.to_string(),
+        nonce: block.header.nonce,
+        difficulty: block.header.difficulty.to_string(),
+        timestamp: block.header.timestamp,
+        merkle_root: block.header.merkle_root.to_string(),
+        state_root: block.header.state_root.to_string(),
+        size: bincode::serialized_size(block).unwrap() as usize,
+        coinbase: CoinbaseInfo {
+            receiver: block.content.coinbase.receiver.to_hex_string(),
+            value: block.content.coinbase.value,
+        },
+        transactions,
+    })
+}
+
+/// Describe a transaction pending in the mempool or confirmed in the longest chain
+pub fn transaction_info(blockchain: &Blockchain, mempool: &Mempool, txn_hash: &H256) -> Option<TransactionInfo> {
+    if let Some(txn) = mempool.get(txn_hash) {
+        return Some(pending_transaction(mempool, txn));
+    }
+    let (block_hash, position) = blockchain.locate_transaction(txn_hash)?;
+    confirmed_transaction(blockchain, &block_hash, position)
+}
+
+/// Describe an account at the tip with its pending and latest confirmed transactions
+pub fn account_info(blockchain: &Blockchain, mempool: &Mempool, address: &Address) -> AccountInfo {
+    let state = blockchain.get_state(&blockchain.tip()).unwrap();
+    let (nonce, balance) = state.get(address).unwrap_or((0, 0));
+    let pending = mempool.sender_transactions(address)
+        .map(|txn| pending_transaction(mempool, txn))
+        .collect();
+    let recent = blockchain.recent_transactions(address, RECENT_TRANSACTIONS).iter()
+        .filter_map(|(block_hash, position)| confirmed_transaction(blockchain, block_hash, *position))
+        .collect();
+
+    AccountInfo {
+        address: address.to_hex_string(),
+        nonce,
+        balance,
+        pending,
+        recent,
+    }
+}
+
+/// Describe the transaction at `position` in a block
+pub fn confirmed_transaction(blockchain: &Blockchain, block_hash: &H256, position: usize) -> Option<TransactionInfo> {
+    let txn = blockchain.get_block(block_hash).ok()?.content.transactions.get(position)?;
+    let mut info = transaction_fields(txn, TransactionState::Confirmed);
+    info.block = Some(block_hash.to_string());
+    info.height = blockchain.get_height(block_hash).ok();
+    info.position = Some(position);
+    info.confirmations = blockchain.confirmations(block_hash);
+    Some(info)
+}
+
+fn pending_transaction(mempool: &Mempool, txn: &SignedTransaction) -> TransactionInfo {
+    let status = match mempool.status(&txn.hash()) {
+        Some(TransactionStatus::Ready) => TransactionState::Ready,
+        _ => TransactionState::Future,
+    };
+    transaction_fields(txn, status)
+}
+
+// Fields of a transaction that do not depend on where it is
+fn transaction_fields(txn: &SignedTransaction, status: TransactionState) -> TransactionInfo {
+    TransactionInfo {
+        hash: txn.hash().to_string(),
+        sender: Address::from_public_key_bytes(&txn.public_key).to_hex_string(),
+        receiver: txn.transaction.receiver.to_hex_string(),
+        account_nonce: txn.transaction.account_nonce,
+        value: txn.transaction.value,
+        fee: txn.transaction.fee,
+        status,
+        block: None,
+        height: None,
+        position: None,
+        confirmations: 0,
+    }
+}
@@ -0,0 +1,199 @@
+use serde::{Deserialize, Serialize};
+use crate::blockchain::{Blockchain, BlockError, ChainUpdate};
+use crate::miner;
+use crate::types::{
+    address::Address,
+    block::{Block, Content, Header},
+    hash::H256,
+    mempool::Mempool,
+    transaction::{CoinbaseTransaction, SignedTransaction},
+};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Serialize)]
+pub struct BlockTemplate {
+    height: u64,
+    parent: String,
+    difficulty: String,
+    merkle_root: String,
+    state_root: String,
+    timestamp: u128,              // current time, which the miner may roll forward
+    min_timestamp: u128,          // earliest timestamp the block may carry
+    coinbase_value: u128,
+    coinbase: String,             // bincode of the coinbase in hex, ending with the 8-byte extra nonce
+    coinbase_branch: Vec<String>, // merkle path of the coinbase, which is leaf 0, up to the merkle root
+    header: String,               // bincode of the header in hex, with a zero nonce
+    transactions: Vec<String>,    // bincode of each transaction in hex, in block order
+}
+
+/// Build a block template paying `receiver` on the tip. Nothing is kept: a solution carries
+/// everything needed to rebuild its block.
+pub fn block_template(blockchain: &Blockchain, mempool: &Mempool, receiver: Address) -> Result<BlockTemplate, BlockError> {
+    let Block { mut header, content } = miner::block_template(blockchain, mempool, receiver)?;
+    let min_timestamp = header.timestamp;
+    header.timestamp = min_timestamp.max(SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis());
+
+    Ok(BlockTemplate {
+        height: content.coinbase.height,
+        parent: header.parent.to_string(),
+        difficulty: header.difficulty.to_string(),
+        merkle_root: header.merkle_root.to_string(),
+        state_root: header.state_root.to_string(),
+        timestamp: header.timestamp,
+        min_timestamp,
+        coinbase_value: content.coinbase.value,
+        coinbase: hex::encode(bincode::serialize(&content.coinbase).unwrap()),
+        coinbase_branch: content.merkle_tree().proof(0).iter().map(|hash| hash.to_string()).collect(),
+        header: hex::encode(bincode::serialize(&header).unwrap()),
+        transactions: content.transactions.iter()
+            .map(|txn| hex::encode(bincode::serialize(txn).unwrap()))
+            .collect(),
+    })
+}
+
+/// A solved block template, as sent by an external miner
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Solution {
+    pub parent: String,
+    pub receiver: String,             // paid by the coinbase
+    pub timestamp: u128,
+    pub nonce: u32,
+    pub extra_nonce: u64,
+    pub transactions: Vec<String>,    // as in the template
+}
+
+/// The reason a solution was not accepted
+#[derive(Debug)]
+pub enum SubmitError {
+    Malformed(String),
+    Block(BlockError),
+}
+
+impl SubmitError {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            SubmitError::Malformed(_) => "malformed",
+            SubmitError::Block(e) => e.kind(),
+        }
+    }
+}
+
+impl std::fmt::Display for SubmitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SubmitError::Malformed(message) => write!(f, "{}", message),
+            SubmitError::Block(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// Rebuild the block of a solution, paying the receiver the subsidy and the fees of its
+/// transactions, and insert it into the blockchain, bringing the mempool in line with the
+/// new main chain
+pub fn submit_block(blockchain: &mut Blockchain, mempool: &mut Mempool, solution: &Solution) -> Result<(Block, ChainUpdate), SubmitError> {
+    let malformed = |field: &str, e: String| SubmitError::Malformed(format!("error parsing {}: {}", field, e));
+    let parent = solution.parent.parse::<H256>().map_err(|e| malformed("parent", e.to_string()))?;
+    let receiver = solution.receiver.parse::<Address>().map_err(|e| malformed("receiver", e.to_string()))?;
+    let transactions = solution.transactions.iter()
+        .map(|txn| {
+            let bytes = hex::decode(txn).map_err(|e| malformed("transaction", e.to_string()))?;
+            bincode::deserialize::<SignedTransaction>(&bytes).map_err(|e| malformed("transaction", e.to_string()))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let height = match blockchain.get_height(&parent) {
+        Ok(height) => height + 1,
+        Err(_) => return Err(SubmitError::Block(BlockError::Orphan { parent })),
+    };
+    let fees = transactions.iter().fold(0u128, |fees, txn| fees.saturating_add(txn.transaction.fee));
+    let coinbase = CoinbaseTransaction {
+        height,
+        receiver,
+        value: blockchain.params().block_subsidy(height).saturating_add(fees),
+        extra_nonce: solution.extra_nonce,
+    };
+    let content = Content { coinbase, transactions };
+    let header = Header {
+        parent,
+        nonce: solution.nonce,
+        difficulty: blockchain.next_difficulty(&parent).unwrap(),
+        timestamp: solution.timestamp,
+        merkle_root: content.merkle_root(),
+        state_root: blockchain.state_root_after(&parent, &content).map_err(SubmitError::Block)?,
+    };
+
+    let block = Block { header, content };
+    let update = blockchain.insert(&block).map_err(SubmitError::Block)?;
+    mempool.update_chain(blockchain, &update);
+    Ok((block, update))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{block_template, submit_block, Solution};
+    use crate::blockchain::{test_params, Blockchain};
+    use crate::blockchain::tests::{genesis_address, signed_transaction};
+    use crate::types::{
+        address::Address,
+        block::{Block, Content, Header},
+        hash::{H256, Hashable},
+        mempool::Mempool,
+        merkle,
+        transaction::CoinbaseTransaction,
+    };
+
+    #[test]
+    fn submit_solved_template() {
+        let mut blockchain = Blockchain::with_params(test_params());
+        let mut mempool = Mempool::new();
+        mempool.revalidate(blockchain.get_state(&blockchain.tip()).unwrap());
+        let txn = signed_transaction(0, 1, genesis_address(1), 10, 3);
+        assert_eq!(mempool.insert(txn.clone()).unwrap().replaced, None);
+
+        let receiver = Address::from_public_key_bytes(&[7; 32]);
+        let template = block_template(&blockchain, &mempool, receiver).unwrap();
+        assert_eq!(template.transactions.len(), 1);
+        assert_eq!(template.coinbase_value, blockchain.params().block_subsidy(1) + 3);
+
+        // A miner rolling the extra nonce folds the new coinbase into the merkle root
+        let mut coinbase: CoinbaseTransaction = bincode::deserialize(&hex::decode(&template.coinbase).unwrap()).unwrap();
+        coinbase.extra_nonce = 5;
+        let content = Content { coinbase: coinbase.clone(), transactions: vec![txn.clone()] };
+        let branch: Vec<H256> = template.coinbase_branch.iter().map(|hash| hash.parse().unwrap()).collect();
+        assert!(merkle::verify(&content.merkle_root(), &coinbase.hash(), &branch, 0, 2));
+
+        let mut header: Header = bincode::deserialize(&hex::decode(&template.header).unwrap()).unwrap();
+        header.merkle_root = content.merkle_root();
+        let mut solution = Solution {
+            parent: template.parent.clone(),
+            receiver: receiver.to_string(),
+            timestamp: header.timestamp,
+            nonce: 0,
+            extra_nonce: 5,
+            transactions: template.transactions.clone(),
+        };
+
+        // An unsolved header cannot be inserted
+        while header.hash() <= header.difficulty {
+            header.nonce += 1;
+        }
+        solution.nonce = header.nonce;
+        let error = submit_block(&mut blockchain, &mut mempool, &solution).unwrap_err();
+        assert_eq!(error.kind(), "invalid-proof-of-work");
+
+        // Nor can garbage be rebuilt into a block
+        let garbled = Solution { transactions: vec!["zz".to_string()], ..solution.clone() };
+        assert_eq!(submit_block(&mut blockchain, &mut mempool, &garbled).unwrap_err().kind(), "malformed");
+
+        while header.hash() > header.difficulty {
+            header.nonce += 1;
+        }
+        solution.nonce = header.nonce;
+        let (block, update) = submit_block(&mut blockchain, &mut mempool, &solution).unwrap();
+        assert_eq!(block.hash(), Block { header, content }.hash());
+        assert_eq!(update.connected, vec![block.hash()]);
+        assert_eq!(blockchain.tip(), block.hash());
+        assert_eq!(block.content.coinbase.extra_nonce, 5);
+        assert!(!mempool.contains(&txn.hash()));
+    }
+}
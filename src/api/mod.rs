@@ -1,3 +1,6 @@
+mod explorer;
+mod mining;
+
 use serde::Serialize;
 use crate::blockchain::Blockchain;
 use crate::miner::Handle as MinerHandle;
@@ -5,9 +8,9 @@ use crate::generator::Handle as GeneratorHandle;
 use crate::network::server::Handle as NetworkServerHandle;
 use crate::network::message::Message;
 use crate::types::{
+    address::Address,
     mempool::Mempool,
     hash::{H256, Hashable},
-    block::Content,
     transaction::SignedTransaction,
 };
 
@@ -16,6 +19,7 @@ use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
 use std::thread;
 use tiny_http::Header;
+use tiny_http::Method;
 use tiny_http::Response;
 use tiny_http::Server as HTTPServer;
 use url::Url;
@@ -26,7 +30,7 @@ pub struct Server {
     txn_generator: GeneratorHandle,
     network: NetworkServerHandle,
     blockchain: Arc<Mutex<Blockchain>>,
-    mempool: Arc<Mutex<Mempool>>
+    mempool: Arc<Mutex<Mempool>>,
 }
 
 #[derive(Serialize)]
@@ -35,6 +39,53 @@ struct ApiResponse {
     message: String,
 }
 
+#[derive(Serialize)]
+struct RejectedBlock {
+    hash: String,
+    error: &'static str,
+    transaction_index: Option<usize>,
+    message: String,
+}
+
+#[derive(Serialize)]
+struct SubmittedTransaction {
+    success: bool,
+    hash: Option<String>,
+    replaced: Option<String>,       // pending transaction with the same nonce that was dropped
+    error: Option<&'static str>,    // short name of the rule the transaction failed
+    message: String,
+}
+
+#[derive(Serialize)]
+struct SubmittedBlock {
+    success: bool,
+    hash: Option<String>,
+    error: Option<&'static str>,    // short name of the rule the block failed
+    message: String,
+}
+
+impl SubmittedBlock {
+    fn rejected(error: &'static str, message: String) -> Self {
+        SubmittedBlock { success: false, hash: None, error: Some(error), message }
+    }
+}
+
+impl SubmittedTransaction {
+    fn rejected(error: &'static str, message: String) -> Self {
+        SubmittedTransaction { success: false, hash: None, replaced: None, error: Some(error), message }
+    }
+}
+
+// Decode a request body given as bincode in hex, or as JSON
+fn parse_body<T: serde::de::DeserializeOwned>(body: &str) -> Result<T, String> {
+    let body = body.trim();
+    if body.starts_with('{') {
+        return serde_json::from_str(body).map_err(|e| e.to_string());
+    }
+    let bytes = hex::decode(body).map_err(|e| e.to_string())?;
+    bincode::deserialize(&bytes).map_err(|e| e.to_string())
+}
+
 macro_rules! respond_result {
     ( $req:expr, $success:expr, $message:expr ) => {{
         let content_type = "Content-Type: application/json".parse::<Header>().unwrap();
@@ -65,7 +116,7 @@ impl Server {
         blockchain: &Arc<Mutex<Blockchain>>,
         mempool: &Arc<Mutex<Mempool>>
     ) {
-        let handle = HTTPServer::http(&addr).unwrap();
+        let handle = HTTPServer::http(addr).unwrap();
         let server = Self {
             handle,
             miner: miner.clone(),
@@ -116,6 +167,30 @@ impl Server {
                             miner.start(lambda);
                             respond_result!(req, true, "ok");
                         }
+                        "/miner/pause" => {
+                            miner.pause();
+                            respond_result!(req, true, "ok");
+                        }
+                        "/miner/resume" => {
+                            miner.resume();
+                            respond_result!(req, true, "ok");
+                        }
+                        "/miner/exit" => {
+                            miner.exit();
+                            respond_result!(req, true, "ok");
+                        }
+                        "/miner/status" => {
+                            respond_json!(req, miner.status());
+                        }
+                        "/miner/stats" => {
+                            let stats = miner.stats();
+                            respond_json!(req, stats);
+                        }
+                        "/metrics" => {
+                            let stats = miner.stats();
+                            let content_type = "Content-Type: text/plain; version=0.0.4".parse::<Header>().unwrap();
+                            req.respond(Response::from_string(stats.metrics()).with_header(content_type)).unwrap();
+                        }
                         "/tx-generator/start" => {
                             let params = url.query_pairs();
                             let params: HashMap<_, _> = params.into_owned().collect();
@@ -140,10 +215,144 @@ impl Server {
                             txn_generator.start(theta);
                             respond_result!(req, true, "ok");
                         }
+                        "/tx-generator/pause" => {
+                            txn_generator.pause();
+                            respond_result!(req, true, "ok");
+                        }
+                        "/tx-generator/resume" => {
+                            txn_generator.resume();
+                            respond_result!(req, true, "ok");
+                        }
+                        "/tx-generator/exit" => {
+                            txn_generator.exit();
+                            respond_result!(req, true, "ok");
+                        }
+                        "/tx-generator/status" => {
+                            respond_json!(req, txn_generator.status());
+                        }
                         "/network/ping" => {
                             network.broadcast(Message::Ping(String::from("Test ping")));
                             respond_result!(req, true, "ok");
                         }
+                        "/transaction" => {
+                            if *req.method() != Method::Post {
+                                respond_result!(req, false, "transactions must be submitted with POST");
+                                return;
+                            }
+                            let mut req = req;
+                            let mut body = String::new();
+                            if let Err(e) = req.as_reader().read_to_string(&mut body) {
+                                respond_result!(req, false, format!("error reading body: {}", e));
+                                return;
+                            }
+                            let txn: SignedTransaction = match parse_body(&body) {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    let message = format!("error parsing transaction: {}", e);
+                                    respond_json!(req, SubmittedTransaction::rejected("malformed", message));
+                                    return;
+                                }
+                            };
+
+                            // Accept only transactions that could be mined right after the
+                            // sender's ready transactions, then let the mempool apply its policy
+                            let hash = txn.hash();
+                            let mut mempool = mempool.lock().unwrap();
+                            let result = match mempool.validate(&txn) {
+                                Ok(()) => mempool.insert(txn)
+                                    .map_err(|e| SubmittedTransaction::rejected(e.kind(), e.to_string())),
+                                Err(e) => Err(SubmittedTransaction::rejected(e.kind(), e.to_string())),
+                            };
+                            drop(mempool);
+
+                            match result {
+                                Ok(inserted) => {
+                                    if inserted.improves_template {
+                                        miner.update();
+                                    }
+                                    network.broadcast(Message::NewTransactionHashes(vec![hash]));
+                                    respond_json!(req, SubmittedTransaction {
+                                        success: true,
+                                        hash: Some(hash.to_string()),
+                                        replaced: inserted.replaced.map(|h| h.to_string()),
+                                        error: None,
+                                        message: "ok".to_string(),
+                                    });
+                                }
+                                Err(rejection) => respond_json!(req, rejection),
+                            }
+                        }
+                        "/mining/getblocktemplate" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let address = match params.get("address") {
+                                Some(v) => v,
+                                None => {
+                                    respond_result!(req, false, "missing address");
+                                    return;
+                                }
+                            };
+                            let address = match address.parse::<Address>() {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, format!("error parsing address: {}", e));
+                                    return;
+                                }
+                            };
+                            let blockchain = blockchain.lock().unwrap();
+                            let mempool = mempool.lock().unwrap();
+                            match mining::block_template(&blockchain, &mempool, address) {
+                                Ok(template) => respond_json!(req, template),
+                                Err(e) => respond_result!(req, false, format!("error building block template: {}", e)),
+                            }
+                        }
+                        "/mining/submitblock" => {
+                            if *req.method() != Method::Post {
+                                respond_result!(req, false, "blocks must be submitted with POST");
+                                return;
+                            }
+                            let mut req = req;
+                            let mut body = String::new();
+                            if let Err(e) = req.as_reader().read_to_string(&mut body) {
+                                respond_result!(req, false, format!("error reading body: {}", e));
+                                return;
+                            }
+                            let solution: mining::Solution = match parse_body(&body) {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    let message = format!("error parsing solution: {}", e);
+                                    respond_json!(req, SubmittedBlock::rejected("malformed", message));
+                                    return;
+                                }
+                            };
+
+                            let mut blockchain = blockchain.lock().unwrap();
+                            let mut mempool = mempool.lock().unwrap();
+                            let result = mining::submit_block(&mut blockchain, &mut mempool, &solution);
+                            if let Ok((_, update)) = &result {
+                                miner.chain_updated(update);
+                            }
+                            drop(mempool);
+                            drop(blockchain);
+
+                            // Relay the block like the miner's own, and have the miner follow the tip if it moved
+                            match result {
+                                Ok((block, update)) => {
+                                    let hash = block.hash();
+                                    network.broadcast(Message::NewBlockHashes(vec![hash]));
+                                    if !update.is_empty() {
+                                        miner.update();
+                                    }
+                                    respond_json!(req, SubmittedBlock {
+                                        success: true,
+                                        hash: Some(hash.to_string()),
+                                        error: None,
+                                        message: "ok".to_string(),
+                                    });
+                                }
+                                Err(e) => respond_json!(req, SubmittedBlock::rejected(e.kind(), e.to_string())),
+                            }
+                        }
                         "/blockchain/longest-chain" => {
                             let blockchain = blockchain.lock().unwrap();
                             let v = blockchain.all_blocks_in_longest_chain();
@@ -220,7 +429,7 @@ impl Server {
                             drop(blockchain);
                             
                             let mut acc_info = Vec::new();
-                            for (address, (acc_nonce, balance)) in &state.map {
+                            for (address, (acc_nonce, balance)) in state.accounts() {
                                 let address_str = address.clone().to_hex_string();
                                 let info_str = format!("({}, {}, {})", address_str, acc_nonce, balance);
                                 acc_info.push(info_str);
@@ -229,24 +438,286 @@ impl Server {
 
                             respond_json!(req, acc_info);
                         }
+                        "/blockchain/account-proof" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let address = match params.get("address") {
+                                Some(v) => v,
+                                None => {
+                                    respond_result!(req, false, "missing address");
+                                    return;
+                                }
+                            };
+                            let address = match address.parse::<Address>() {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, format!("error parsing address: {}", e));
+                                    return;
+                                }
+                            };
+
+                            // Prove against the tip unless a block number in the longest chain is given
+                            let blockchain = blockchain.lock().unwrap();
+                            let block_hash = match params.get("block") {
+                                Some(block_num) => {
+                                    let block_num = match block_num.parse::<usize>() {
+                                        Ok(v) => v,
+                                        Err(e) => {
+                                            respond_result!(
+                                                req,
+                                                false,
+                                                format!("error parsing block number: {}", e)
+                                            );
+                                            return;
+                                        }
+                                    };
+                                    let v = blockchain.all_blocks_in_longest_chain();
+                                    if block_num >= v.len() {
+                                        respond_result!(req, false, "given block number is out of bounds");
+                                        return;
+                                    }
+                                    v[block_num]
+                                }
+                                None => blockchain.tip(),
+                            };
+                            let proof = blockchain.account_proof(&block_hash, &address).unwrap();
+                            drop(blockchain);
+
+                            respond_json!(req, proof);
+                        }
+                        "/blockchain/tx-proof" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let txn_hash = match params.get("tx") {
+                                Some(v) => v,
+                                None => {
+                                    respond_result!(req, false, "missing tx");
+                                    return;
+                                }
+                            };
+                            let txn_hash = match txn_hash.parse::<H256>() {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, format!("error parsing tx: {}", e));
+                                    return;
+                                }
+                            };
+
+                            let blockchain = blockchain.lock().unwrap();
+                            let proof = blockchain.transaction_proof(&txn_hash);
+                            drop(blockchain);
+
+                            match proof {
+                                Some(proof) => respond_json!(req, proof),
+                                None => respond_result!(req, false, "transaction not found in the longest chain"),
+                            }
+                        }
+                        "/txindex/tx" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let txn_hash = match params.get("hash") {
+                                Some(v) => v,
+                                None => {
+                                    respond_result!(req, false, "missing hash");
+                                    return;
+                                }
+                            };
+                            let txn_hash = match txn_hash.parse::<H256>() {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, format!("error parsing hash: {}", e));
+                                    return;
+                                }
+                            };
+
+                            let blockchain = blockchain.lock().unwrap();
+                            if blockchain.txindex().is_none() {
+                                respond_result!(req, false, "transaction index is disabled");
+                                return;
+                            }
+                            let txn = blockchain.txindex().unwrap().get(&txn_hash).and_then(|(block_hash, position)| {
+                                explorer::confirmed_transaction(&blockchain, &block_hash, position)
+                            });
+                            drop(blockchain);
+
+                            match txn {
+                                Some(txn) => respond_json!(req, txn),
+                                None => respond_result!(req, false, "transaction not found in the longest chain"),
+                            }
+                        }
+                        "/txindex/address" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let address = match params.get("address") {
+                                Some(v) => v,
+                                None => {
+                                    respond_result!(req, false, "missing address");
+                                    return;
+                                }
+                            };
+                            let address = match address.parse::<Address>() {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, format!("error parsing address: {}", e));
+                                    return;
+                                }
+                            };
+
+                            let blockchain = blockchain.lock().unwrap();
+                            let txindex = match blockchain.txindex() {
+                                Some(txindex) => txindex,
+                                None => {
+                                    respond_result!(req, false, "transaction index is disabled");
+                                    return;
+                                }
+                            };
+                            let history: Vec<_> = txindex
+                                .address_transactions(&address)
+                                .iter()
+                                .filter_map(|txn_hash| txindex.get(txn_hash))
+                                .filter_map(|(block_hash, position)| {
+                                    explorer::confirmed_transaction(&blockchain, &block_hash, position)
+                                })
+                                .collect();
+                            drop(blockchain);
+
+                            respond_json!(req, history);
+                        }
+                        "/explorer/block" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let blockchain = blockchain.lock().unwrap();
+
+                            // Find the block by hash, or by height in the longest chain
+                            let block_hash = if let Some(hash) = params.get("hash") {
+                                match hash.parse::<H256>() {
+                                    Ok(v) => v,
+                                    Err(e) => {
+                                        respond_result!(req, false, format!("error parsing hash: {}", e));
+                                        return;
+                                    }
+                                }
+                            } else if let Some(height) = params.get("height") {
+                                let height = match height.parse::<u64>() {
+                                    Ok(v) => v,
+                                    Err(e) => {
+                                        respond_result!(req, false, format!("error parsing height: {}", e));
+                                        return;
+                                    }
+                                };
+                                match blockchain.block_at_height(height) {
+                                    Some(v) => v,
+                                    None => {
+                                        respond_result!(req, false, "given height is above the tip");
+                                        return;
+                                    }
+                                }
+                            } else {
+                                respond_result!(req, false, "missing hash or height");
+                                return;
+                            };
+
+                            let block = explorer::block_info(&blockchain, &block_hash);
+                            drop(blockchain);
+
+                            match block {
+                                Some(block) => respond_json!(req, block),
+                                None => respond_result!(req, false, "block not found"),
+                            }
+                        }
+                        "/explorer/tx" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let txn_hash = match params.get("hash") {
+                                Some(v) => v,
+                                None => {
+                                    respond_result!(req, false, "missing hash");
+                                    return;
+                                }
+                            };
+                            let txn_hash = match txn_hash.parse::<H256>() {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, format!("error parsing hash: {}", e));
+                                    return;
+                                }
+                            };
+
+                            let blockchain = blockchain.lock().unwrap();
+                            let mempool = mempool.lock().unwrap();
+                            let txn = explorer::transaction_info(&blockchain, &mempool, &txn_hash);
+                            drop(mempool);
+                            drop(blockchain);
+
+                            match txn {
+                                Some(txn) => respond_json!(req, txn),
+                                None => respond_result!(req, false, "transaction not found"),
+                            }
+                        }
+                        "/explorer/account" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let address = match params.get("address") {
+                                Some(v) => v,
+                                None => {
+                                    respond_result!(req, false, "missing address");
+                                    return;
+                                }
+                            };
+                            let address = match address.parse::<Address>() {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, format!("error parsing address: {}", e));
+                                    return;
+                                }
+                            };
+
+                            let blockchain = blockchain.lock().unwrap();
+                            let mempool = mempool.lock().unwrap();
+                            let account = explorer::account_info(&blockchain, &mempool, &address);
+                            drop(mempool);
+                            drop(blockchain);
+
+                            respond_json!(req, account);
+                        }
+                        "/blockchain/rejected-blocks" => {
+                            let blockchain = blockchain.lock().unwrap();
+                            let rejected = blockchain.rejected_blocks();
+                            drop(blockchain);
+
+                            let rejected: Vec<RejectedBlock> = rejected
+                                .into_iter()
+                                .map(|(hash, e)| RejectedBlock {
+                                    hash: hash.to_string(),
+                                    error: e.kind(),
+                                    transaction_index: e.transaction_index(),
+                                    message: e.to_string(),
+                                })
+                                .collect();
+                            respond_json!(req, rejected);
+                        }
                         "/blockchain/num-blocks" => {
                             let blockchain = blockchain.lock().unwrap();
                             let length = blockchain.all_blocks_in_longest_chain().len();
                             respond_json!(req, length);
                         }
+                        "/mempool/stats" => {
+                            let stats = mempool.lock().unwrap().stats();
+                            respond_json!(req, stats);
+                        }
                         "/mempool" => {
                             let mempool = mempool.lock().unwrap();
-                            let map = mempool.map.clone();
-                            drop(mempool);
-
                             let mut all_txns = Vec::new();
-                            for txn in map.values() {
+                            for txn in mempool.transactions() {
                                 let acc_nonce = txn.transaction.account_nonce;
                                 let receiver = txn.transaction.receiver.clone().to_hex_string();
                                 let value = txn.transaction.value;
-                                let info = (acc_nonce, receiver, value);
+                                let fee = txn.transaction.fee;
+                                let status = mempool.status(&txn.hash()).unwrap();
+                                let info = (acc_nonce, receiver, value, fee, status);
                                 all_txns.push(info);
                             }
+                            drop(mempool);
                             
                             respond_json!(req, all_txns);
                         }
@@ -271,3 +742,23 @@ impl Server {
         info!("API server listening at {}", &addr);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::parse_body;
+    use crate::types::transaction::SignedTransaction;
+    use crate::types::hash::Hashable;
+    use crate::blockchain::tests::signed_transaction;
+    use crate::types::address::generate_random_address;
+
+    #[test]
+    fn parse_hex_and_json_transactions() {
+        let txn = signed_transaction(0, 1, generate_random_address(), 10, 1);
+        let hex = hex::encode(bincode::serialize(&txn).unwrap());
+        let json = serde_json::to_string(&txn).unwrap();
+        assert_eq!(parse_body::<SignedTransaction>(&hex).unwrap().hash(), txn.hash());
+        assert_eq!(parse_body::<SignedTransaction>(&format!(" {}\n", json)).unwrap().hash(), txn.hash());
+        assert!(parse_body::<SignedTransaction>("zz").is_err());
+        assert!(parse_body::<SignedTransaction>(&hex[..hex.len() - 2]).is_err());
+    }
+}
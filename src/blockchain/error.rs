@@ -0,0 +1,132 @@
+use crate::types::hash::H256;
+use std::fmt;
+
+/// The reason a transaction is invalid against a State
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TransactionError {
+    InvalidSignature,
+    UnknownSender,
+    InvalidNonce { expected: u128, found: u128 },
+    InsufficientBalance { balance: u128, required: u128 },
+}
+
+/// The reason a block could not be inserted into the Blockchain
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlockError {
+    Orphan { parent: H256 },
+    Duplicate,
+    TooLarge { size: usize, limit: usize },
+    InvalidProofOfWork,
+    InvalidMerkleRoot,
+    InvalidDifficulty { expected: H256, found: H256 },
+    TimestampTooOld { median_time_past: u128, found: u128 },
+    TimestampTooNew { limit: u128, found: u128 },
+    InvalidCoinbaseHeight { expected: u64, found: u64 },
+    InvalidCoinbaseValue { expected: u128, found: u128 },
+    InvalidTransaction { index: usize, error: TransactionError },
+    InvalidStateRoot { expected: H256, found: H256 },
+    Storage(String),
+}
+
+impl TransactionError {
+    /// Short machine-readable name of the rule that failed
+    pub fn kind(&self) -> &'static str {
+        match self {
+            TransactionError::InvalidSignature => "invalid-signature",
+            TransactionError::UnknownSender => "unknown-sender",
+            TransactionError::InvalidNonce { .. } => "invalid-nonce",
+            TransactionError::InsufficientBalance { .. } => "insufficient-balance",
+        }
+    }
+}
+
+impl BlockError {
+    /// Short machine-readable name of the rule that failed
+    pub fn kind(&self) -> &'static str {
+        match self {
+            BlockError::Orphan { .. } => "orphan",
+            BlockError::Duplicate => "duplicate",
+            BlockError::TooLarge { .. } => "too-large",
+            BlockError::InvalidProofOfWork => "invalid-proof-of-work",
+            BlockError::InvalidMerkleRoot => "invalid-merkle-root",
+            BlockError::InvalidDifficulty { .. } => "invalid-difficulty",
+            BlockError::TimestampTooOld { .. } => "timestamp-too-old",
+            BlockError::TimestampTooNew { .. } => "timestamp-too-new",
+            BlockError::InvalidCoinbaseHeight { .. } => "invalid-coinbase-height",
+            BlockError::InvalidCoinbaseValue { .. } => "invalid-coinbase-value",
+            BlockError::InvalidTransaction { .. } => "invalid-transaction",
+            BlockError::InvalidStateRoot { .. } => "invalid-state-root",
+            BlockError::Storage(_) => "storage",
+        }
+    }
+
+    /// Index of the offending transaction, if a transaction check failed
+    pub fn transaction_index(&self) -> Option<usize> {
+        match self {
+            BlockError::InvalidTransaction { index, .. } => Some(*index),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for TransactionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TransactionError::InvalidSignature => write!(f, "invalid signature"),
+            TransactionError::UnknownSender => write!(f, "sender account does not exist"),
+            TransactionError::InvalidNonce { expected, found } => {
+                write!(f, "invalid account nonce {}, expected {}", found, expected)
+            }
+            TransactionError::InsufficientBalance { balance, required } => {
+                write!(f, "insufficient balance {}, requires {}", balance, required)
+            }
+        }
+    }
+}
+
+impl fmt::Display for BlockError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BlockError::Orphan { parent } => write!(f, "parent block {} is unknown", parent),
+            BlockError::Duplicate => write!(f, "block already exists"),
+            BlockError::TooLarge { size, limit } => {
+                write!(f, "block size {} exceeds limit {}", size, limit)
+            }
+            BlockError::InvalidProofOfWork => write!(f, "block hash exceeds its difficulty"),
+            BlockError::InvalidMerkleRoot => write!(f, "merkle root does not match transactions"),
+            BlockError::InvalidDifficulty { expected, found } => {
+                write!(f, "difficulty {} does not match expected {}", found, expected)
+            }
+            BlockError::TimestampTooOld { median_time_past, found } => {
+                write!(f, "timestamp {} is not after median time past {}", found, median_time_past)
+            }
+            BlockError::TimestampTooNew { limit, found } => {
+                write!(f, "timestamp {} is after the allowed limit {}", found, limit)
+            }
+            BlockError::InvalidCoinbaseHeight { expected, found } => {
+                write!(f, "coinbase height {} does not match block height {}", found, expected)
+            }
+            BlockError::InvalidCoinbaseValue { expected, found } => {
+                write!(f, "coinbase value {} does not match block reward {}", found, expected)
+            }
+            BlockError::InvalidTransaction { index, error } => {
+                write!(f, "transaction {} is invalid: {}", index, error)
+            }
+            BlockError::InvalidStateRoot { expected, found } => {
+                write!(f, "state root {} does not match resulting state {}", found, expected)
+            }
+            BlockError::Storage(e) => write!(f, "failed to persist block: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for TransactionError {}
+
+impl std::error::Error for BlockError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            BlockError::InvalidTransaction { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
@@ -1,190 +1,695 @@
+pub mod error;
+pub mod store;
+pub mod txindex;
+
 use crate::types::{
     address::Address,
     block::{Block, Content, Header},
     hash::{H256, Hashable},
     transaction,
-    transaction::SignedTransaction,
-    merkle::MerkleTree,
-    state::State
+    transaction::{CoinbaseTransaction, SignedTransaction},
+    merkle::TransactionProof,
+    state::State,
+    sparse_merkle::{AccountProof, SparseMerkleTree},
+    uint::U256
 };
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 use hex_literal::hex;
+use log::error;
 use ring::signature::{Ed25519KeyPair, KeyPair};
+use store::{BlockStore, Record};
+use txindex::TxIndex;
+
+pub use error::{BlockError, TransactionError};
+
+/// Easiest target a block of the main chain may have; also its genesis block's difficulty
+pub const MAX_TARGET: [u8; 32] = hex!("0000100000000000000000000000000000000000000000000000000000000000");
+
+/// Number of blocks between two difficulty adjustments
+pub const DIFFICULTY_ADJUSTMENT_INTERVAL: u64 = 10;
+
+/// Desired time between two consecutive blocks, in milliseconds
+pub const TARGET_BLOCK_TIME: u64 = 10_000;
+
+/// Largest factor by which a single adjustment may change the difficulty
+pub const MAX_ADJUSTMENT_FACTOR: u64 = 4;
+
+/// Number of ancestors whose median timestamp a new block must exceed
+pub const MEDIAN_TIME_SPAN: usize = 11;
+
+/// How far ahead of the local clock a block's timestamp may be, in milliseconds
+pub const MAX_FUTURE_BLOCK_TIME: u128 = 2 * 60 * 60 * 1000;
+
+/// Largest serialized size of a block, in bytes
+pub const MAX_BLOCK_SIZE: usize = 100_000;
+
+/// Block subsidy paid to the miner of the first blocks of the main chain
+pub const INITIAL_BLOCK_SUBSIDY: u128 = 100;
+
+/// Number of blocks after which the block subsidy of the main chain halves
+pub const SUBSIDY_HALVING_INTERVAL: u64 = 210;
+
+// Number of recently rejected blocks remembered for diagnostics
+const REJECTED_BLOCKS_KEPT: usize = 100;
+
+/// Consensus parameters a Blockchain validates its blocks against
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChainParams {
+    pub max_target: H256,                 // easiest target a block may have; also the genesis block's difficulty
+    pub initial_block_subsidy: u128,      // subsidy minted by the coinbase of the first blocks
+    pub subsidy_halving_interval: u64,    // blocks after which the subsidy halves, or 0 to never halve
+}
+
+impl Default for ChainParams {
+    /// Parameters of the main chain
+    fn default() -> Self {
+        ChainParams {
+            max_target: MAX_TARGET.into(),
+            initial_block_subsidy: INITIAL_BLOCK_SUBSIDY,
+            subsidy_halving_interval: SUBSIDY_HALVING_INTERVAL,
+        }
+    }
+}
+
+impl ChainParams {
+    /// Block subsidy of the block at `height`, halving every `subsidy_halving_interval` blocks
+    pub fn block_subsidy(&self, height: u64) -> u128 {
+        let halvings = height.checked_div(self.subsidy_halving_interval).unwrap_or(0);
+        if halvings >= u128::BITS as u64 {
+            return 0;
+        }
+        self.initial_block_subsidy >> halvings
+    }
+}
+
+/// Parameters of the chains built by tests, which mine every block they insert and so run
+/// against a much easier target
+#[cfg(any(test, feature = "test-utilities"))]
+pub fn test_params() -> ChainParams {
+    ChainParams {
+        max_target: hex!("1000000000000000000000000000000000000000000000000000000000000000").into(),
+        ..ChainParams::default()
+    }
+}
 
 // A BlockNode is a node in the Blockchain
 pub struct BlockNode {
     block: Block, 
     height: u64,
-    pub state: State
+    chainwork: U256,    // total work of the chain ending at this block
+    pub state: Arc<State>,
+    tree: SparseMerkleTree    // over `state`, sharing the untouched subtrees of the parent's
+}
+
+/// How the chain with the most work changed when a block was inserted
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ChainUpdate {
+    pub disconnected: Vec<H256>,    // blocks that left the chain, from the old tip down
+    pub connected: Vec<H256>,       // blocks that joined the chain, up to the new tip
+}
+
+impl ChainUpdate {
+    /// Whether the tip stayed where it was
+    pub fn is_empty(&self) -> bool {
+        self.disconnected.is_empty() && self.connected.is_empty()
+    }
 }
 
 // A Blockchain
 pub struct Blockchain {
+    params: ChainParams,
     map: HashMap<H256, BlockNode>,
-    tip: H256
+    tip: H256,
+    store: Option<BlockStore>,    // on-disk log of inserted blocks, if persistent
+    txindex: Option<TxIndex>,     // transactions of the longest chain, if indexed
+    rejected: VecDeque<(H256, BlockError)>    // most recent rejections, oldest first
+}
+
+/// Check a transaction against the state it would be applied to
+pub fn validate_transaction(state: &State, txn: &SignedTransaction) -> Result<(), TransactionError> {
+    // Check transaction validity
+    if !transaction::verify(&txn.transaction, &txn.public_key, &txn.signature) {
+        return Err(TransactionError::InvalidSignature);
+    }
+
+    // Check account state
+    let sender_address = Address::from_public_key_bytes(&txn.public_key);
+    let (sender_nonce, sender_balance) = match state.get(&sender_address) {
+        Some(acc_info) => acc_info,
+        None => return Err(TransactionError::UnknownSender),
+    };
+
+    // Check if the new account nonce in the transaction is correct
+    if sender_nonce + 1 != txn.transaction.account_nonce {
+        return Err(TransactionError::InvalidNonce {
+            expected: sender_nonce + 1,
+            found: txn.transaction.account_nonce
+        });
+    }
+
+    // Check if sender's balance is enough to cover the value and fee
+    let required = txn.transaction.value.saturating_add(txn.transaction.fee);
+    if sender_balance < required {
+        return Err(TransactionError::InsufficientBalance { balance: sender_balance, required });
+    }
+
+    Ok(())
+}
+
+/// Apply a valid transaction to the state, leaving its fee to be credited to the miner
+pub fn apply_transaction(state: &mut State, txn: &SignedTransaction) {
+    let sender_address = Address::from_public_key_bytes(&txn.public_key);
+    let value = txn.transaction.value;
+
+    // Txn value and fee are subtracted from sender's balance
+    let (sender_nonce, sender_balance) = state.get(&sender_address).unwrap();
+    state.insert(sender_address, (sender_nonce + 1, sender_balance - value - txn.transaction.fee));
+
+    // Txn value is added to receiver's balance, creating its account if needed
+    let (receiver_nonce, receiver_balance) = state.get(&txn.transaction.receiver).unwrap_or((0, 0));
+    state.insert(txn.transaction.receiver, (receiver_nonce, receiver_balance + value));
+}
+
+/// Compute the state after a block at `height` with the given content, checking every
+/// transaction against the state left by the ones before it. The new state only stores the
+/// accounts touched by the block and shares the rest with its parent.
+pub fn next_state(params: &ChainParams, parent_state: &Arc<State>, content: &Content, height: u64) -> Result<State, BlockError> {
+    let coinbase = &content.coinbase;
+    if coinbase.height != height {
+        return Err(BlockError::InvalidCoinbaseHeight { expected: height, found: coinbase.height });
+    }
+
+    let mut state = State::child(parent_state);
+    let mut fees: u128 = 0;
+    for (index, txn) in content.transactions.iter().enumerate() {
+        validate_transaction(&state, txn)
+            .map_err(|error| BlockError::InvalidTransaction { index, error })?;
+        apply_transaction(&mut state, txn);
+        fees += txn.transaction.fee;
+    }
+
+    // Check if coinbase mints exactly the subsidy plus the collected fees
+    let reward = params.block_subsidy(height) + fees;
+    if coinbase.value != reward {
+        return Err(BlockError::InvalidCoinbaseValue { expected: reward, found: coinbase.value });
+    }
+
+    // Credit the block reward to the miner
+    let (miner_nonce, miner_balance) = state.get(&coinbase.receiver).unwrap_or((0, 0));
+    state.insert(coinbase.receiver, (miner_nonce, miner_balance + coinbase.value));
+
+    Ok(state)
+}
+
+/// The state of the genesis block, holding 3 accounts
+pub fn genesis_state() -> State {
+    let mut state = State::new();
+    for seed in 0..3 {
+        let key = Ed25519KeyPair::from_seed_unchecked(&[seed;32]).unwrap();
+        let public_key = key.public_key().as_ref().to_vec();
+        let addr = Address::from_public_key_bytes(&public_key);
+        
+        // Only first account has a nonzero balance
+        let balance = if seed == 0 { 10000u128 } else { 0 };
+        state.insert(addr, (0, balance));    // account_nonce initialized to 0
+    }
+    state
+}
+
+impl Default for Blockchain {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 // Implement functions for the Blockchain
 impl Blockchain {
-    /// Create a new blockchain, only containing the genesis block
+    /// Create a new blockchain of the main chain, only containing the genesis block
     pub fn new() -> Self {
+        Self::with_params(ChainParams::default())
+    }
+
+    /// Create a new blockchain with the given parameters, only containing the genesis block
+    pub fn with_params(params: ChainParams) -> Self {
         let mut map = HashMap::new();
 
         let genesis_parent: H256 = (hex!("0000000000000000000000000000000000000000000000000000000000000000")).into();
         let nonce: u32 = 0;
         
+        let coinbase = CoinbaseTransaction::default();    // genesis mints nothing
         let transactions: Vec<SignedTransaction> = Vec::new();
-        let merkle_tree = MerkleTree::new(&transactions);
-        let merkle_root = merkle_tree.root();
+        let content = Content { coinbase, transactions };
+        let merkle_root = content.merkle_root();
+        let state = genesis_state();
+        let tree = SparseMerkleTree::new(&state);
+        let state_root = tree.root();
         
-        let difficulty: H256 = hex!("0000100000000000000000000000000000000000000000000000000000000000").into();
+        let difficulty: H256 = params.max_target;
         let timestamp: u128 = 0;
 
-        let content = Content { transactions };
-
         let header = Header {
             parent: genesis_parent,
-            nonce: nonce,
-            difficulty: difficulty,
-            timestamp: timestamp,
-            merkle_root: merkle_root
+            nonce,
+            difficulty,
+            timestamp,
+            merkle_root,
+            state_root
         };
 
         let genesis_block = Block { header, content };
         let tip = genesis_block.hash();
         println!("GENISIS HASH: {}", tip);
 
-        // Initialize the genesis block node's state with 3 accounts
-        let mut state = State::new();
-        for seed in 0..3 {
-            let key = Ed25519KeyPair::from_seed_unchecked(&[seed;32]).unwrap();
-            let public_key = key.public_key().as_ref().to_vec();
-            let addr = Address::from_public_key_bytes(&public_key);
-            
-            // Only first account has a nonzero balance
-            let balance = if seed == 0 { 10000u128 } else { 0 };
-            state.map.insert(addr, (0, balance));    // account_nonce initialized to 0
+        let chainwork = U256::work(&genesis_block.get_difficulty());
+        map.insert(genesis_block.hash(), BlockNode { block: genesis_block, height: 0, chainwork, state: Arc::new(state), tree });
+
+        Blockchain { params, map, tip, store: None, txindex: None, rejected: VecDeque::new() }
+    }
+
+    /// Open a persistent blockchain stored in `dir`, rebuilding it from the blocks on disk.
+    /// Every stored block was valid when it was written, so one that no longer inserts means
+    /// the log does not belong to this chain and opening it fails.
+    pub fn open<P: AsRef<Path>>(dir: P, params: ChainParams) -> io::Result<Self> {
+        let (store, records) = BlockStore::open(dir)?;
+        let mut blockchain = Blockchain::with_params(params);
+        let mut stored_tip = None;
+
+        // Replay the log; the store is attached afterwards so nothing is written back
+        for record in records {
+            match record {
+                Record::Block { block, height } => {
+                    stored_tip = None;    // only a tip recorded after the last block applies
+                    if let Err(e) = blockchain.insert(&block) {
+                        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                                  format!("stored block {} cannot be replayed: {}", block.hash(), e)));
+                    }
+                    let replayed = blockchain.map[&block.hash()].height;
+                    if replayed != height {
+                        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                                  format!("stored block {} has height {}, expected {}",
+                                                          block.hash(), height, replayed)));
+                    }
+                }
+                Record::Tip(hash) => stored_tip = Some(hash),
+            }
         }
 
-        map.insert(genesis_block.hash(), BlockNode { block: genesis_block, height: 0, state: state });
+        // Restore the stored tip if the log ends with one
+        if let Some(hash) = stored_tip {
+            if blockchain.map.contains_key(&hash) {
+                blockchain.tip = hash;
+            }
+        }
 
-        Blockchain { map, tip }
+        blockchain.store = Some(store);
+        Ok(blockchain)
     }
 
-    /// Insert a block into blockchain
-    pub fn insert(&mut self, block: &Block) -> Result<(), bool> {
+    /// Index the transactions of the longest chain, keeping the index up to date as blocks
+    /// join and leave it
+    pub fn enable_txindex(&mut self) {
+        let mut txindex = TxIndex::new();
+        for hash in self.all_blocks_in_longest_chain() {
+            txindex.connect(&self.map[&hash].block);
+        }
+        self.txindex = Some(txindex);
+    }
+
+    /// Get the transaction index, if it is enabled
+    pub fn txindex(&self) -> Option<&TxIndex> {
+        self.txindex.as_ref()
+    }
+
+    /// Insert a block into blockchain, returning how the chain with the most work changed,
+    /// or recording why the block was rejected on failure
+    pub fn insert(&mut self, block: &Block) -> Result<ChainUpdate, BlockError> {
+        let result = self.validate_and_insert(block);
+
+        // Orphans and duplicates are expected during sync and are not rejections
+        if let Err(e) = &result {
+            if !matches!(e, BlockError::Orphan { .. } | BlockError::Duplicate) {
+                if self.rejected.len() == REJECTED_BLOCKS_KEPT {
+                    self.rejected.pop_front();
+                }
+                self.rejected.push_back((block.hash(), e.clone()));
+            }
+        }
+
+        result
+    }
+
+    fn validate_and_insert(&mut self, block: &Block) -> Result<ChainUpdate, BlockError> {
         let parent_node = match self.map.get(&block.get_parent()) {
             Some(node) => node,    // parent exists in hashmap
             None => {
                 // parent is missing in hashmap, so return an error
-                return Err(true);
+                return Err(BlockError::Orphan { parent: block.get_parent() });
             }
         };
 
         // Check if block is a duplicate
         if self.map.contains_key(&block.hash()) {
-            return Err(false);   // block already exists
+            return Err(BlockError::Duplicate);
         }
 
-        let height = parent_node.height + 1;
-        let parent_state = parent_node.state.clone();
-        
-        // Validate all transactions in the block
-        for txn in block.content.transactions.iter() {
-            // Check transaction validity
-            if !transaction::verify(&txn.transaction, &txn.public_key, &txn.signature) {
-                return Err(false);       // transaction verification failed
-            }
+        // Check if block is within the size limit
+        let size = bincode::serialized_size(block).unwrap() as usize;
+        if size > MAX_BLOCK_SIZE {
+            return Err(BlockError::TooLarge { size, limit: MAX_BLOCK_SIZE });
+        }
 
-            // Check account state
-            let sender_address = Address::from_public_key_bytes(&txn.public_key);
-            let sender_info = match parent_state.map.get(&sender_address) {
-                Some(acc_info) => acc_info,
-                None => {
-                    return Err(false);   // sender's address not in state hashmap
-                },
-            };
-            let sender_nonce = sender_info.0;
-            let sender_balance = sender_info.1;
-
-            // Check if the new account nonce in the transaction is correct
-            if sender_nonce + 1 != txn.transaction.account_nonce {
-                return Err(false);      // transaction has invalid account nonce
-            }
-            
-            // Check if sender's balance is enough
-            if sender_balance < txn.transaction.value {
-                return Err(false);      // balance is not enough
-            }
+        // Check if block hash satisfies its difficulty
+        if block.hash() > block.get_difficulty() {
+            return Err(BlockError::InvalidProofOfWork);
         }
 
-        // All the transactions are valid, so create a new state for them
-        let mut new_state = parent_state.clone();
-        for txn in block.content.transactions.iter() {
-            let sender_address = Address::from_public_key_bytes(&txn.public_key);
-            let receiver_address = txn.transaction.receiver;
-            let value = txn.transaction.value;
+        // Check if header commits to the block's transactions
+        if block.header.merkle_root != block.content.merkle_root() {
+            return Err(BlockError::InvalidMerkleRoot);
+        }
 
-            if let Some(sender_info) = parent_state.map.get(&sender_address) {
-                // Txn value is subracted from sender's balance
-                let new_sender_balance = sender_info.1 - value;
-                new_state.map.insert(sender_address, (sender_info.0 + 1, new_sender_balance));
-            }
+        // Check if block has the difficulty required by its position in the chain
+        let expected = self.expected_difficulty(parent_node);
+        if block.get_difficulty() != expected {
+            return Err(BlockError::InvalidDifficulty { expected, found: block.get_difficulty() });
+        }
+
+        // Check if timestamp is after the median of recent blocks and not too far in the future
+        let median_time_past = self.median_time_past(parent_node);
+        if block.header.timestamp <= median_time_past {
+            return Err(BlockError::TimestampTooOld { median_time_past, found: block.header.timestamp });
+        }
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
+        if block.header.timestamp > now + MAX_FUTURE_BLOCK_TIME {
+            return Err(BlockError::TimestampTooNew {
+                limit: now + MAX_FUTURE_BLOCK_TIME,
+                found: block.header.timestamp
+            });
+        }
+
+        let height = parent_node.height + 1;
+        let chainwork = parent_node.chainwork.saturating_add(&U256::work(&block.get_difficulty()));
+        let new_state = next_state(&self.params, &parent_node.state, &block.content, height)?;
+
+        // Check if header commits to the state left by the block
+        let tree = parent_node.tree.update(new_state.changes());
+        let state_root = tree.root();
+        if block.header.state_root != state_root {
+            return Err(BlockError::InvalidStateRoot { expected: state_root, found: block.header.state_root });
+        }
         
-            if let Some(receiver_info) = parent_state.map.get(&receiver_address) {
-                // Txn value is added to receiver's balance
-                let new_receiver_balance = receiver_info.1 + value;
-                new_state.map.insert(receiver_address, (receiver_info.0, new_receiver_balance));
+        // Persist the block before it becomes visible
+        if let Some(store) = self.store.as_mut() {
+            if let Err(e) = store.append(&Record::Block { block: Box::new(block.clone()), height }) {
+                return Err(BlockError::Storage(e.to_string()));
             }
         }
-        
+
         let blocknode = BlockNode { 
             block: block.clone(), 
-            height: height,
-            state: new_state.clone()
+            height,
+            chainwork,
+            state: Arc::new(new_state),
+            tree
         }; 
 
         // Insert blocknode into hashmap
         self.map.insert(block.hash(), blocknode);
 
-        // Update tip
+        // Update tip to the chain with the most work; on a tie the tip seen first is kept
         let tip_node = self.map.get(&self.tip).unwrap();        
-        if height > tip_node.height {
-            self.tip = block.hash();
+        if chainwork <= tip_node.chainwork {
+            return Ok(ChainUpdate::default());    // Successfully inserted block off the main chain
+        }
+
+        let update = self.chain_update(self.tip, block.hash());
+        self.tip = block.hash();
+        if let Some(txindex) = self.txindex.as_mut() {
+            for hash in update.disconnected.iter() {
+                txindex.disconnect(&self.map[hash].block);
+            }
+            for hash in update.connected.iter() {
+                txindex.connect(&self.map[hash].block);
+            }
         }
+        if let Some(store) = self.store.as_mut() {
+            if let Err(e) = store.append(&Record::Tip(self.tip)) {
+                error!("Failed to persist tip {}: {}", self.tip, e);
+            }
+        }
+
+        Ok(update)    // Successfully inserted block as the new tip
+    }
+
+    // Blocks leaving and joining the main chain when the tip moves from `old_tip` to `new_tip`,
+    // found by walking both back to their common ancestor
+    fn chain_update(&self, old_tip: H256, new_tip: H256) -> ChainUpdate {
+        let mut update = ChainUpdate::default();
+        let (mut old, mut new) = (old_tip, new_tip);
+        while old != new {
+            let old_height = self.map[&old].height;
+            let new_height = self.map[&new].height;
+            if old_height >= new_height {
+                update.disconnected.push(old);
+                old = self.map[&old].block.get_parent();
+            }
+            if new_height >= old_height {
+                update.connected.push(new);
+                new = self.map[&new].block.get_parent();
+            }
+        }
+        update.connected.reverse();
+        update
+    }
+
+    /// Get the difficulty required of a block whose parent is `parent_hash`
+    pub fn next_difficulty(&self, parent_hash: &H256) -> Result<H256, &'static str> {
+        match self.map.get(parent_hash) {
+            Some(node) => Ok(self.expected_difficulty(node)),
+            None => Err("Block does not exist in blockchain."),
+        }
+    }
+
+    // Retarget every DIFFICULTY_ADJUSTMENT_INTERVAL blocks from the time the last interval took;
+    // all other blocks keep their parent's difficulty
+    fn expected_difficulty(&self, parent_node: &BlockNode) -> H256 {
+        let height = parent_node.height + 1;
+        let parent_difficulty = parent_node.block.get_difficulty();
+        if !height.is_multiple_of(DIFFICULTY_ADJUSTMENT_INTERVAL) {
+            return parent_difficulty;
+        }
+
+        // Find the first block of the interval that the parent closes
+        let mut first_node = parent_node;
+        for _ in 1..DIFFICULTY_ADJUSTMENT_INTERVAL {
+            first_node = &self.map[&first_node.block.get_parent()];
+        }
+
+        // Compare the time the interval took with the time it should have taken
+        let expected_span = TARGET_BLOCK_TIME * (DIFFICULTY_ADJUSTMENT_INTERVAL - 1);
+        let actual_span = parent_node.block.header.timestamp
+            .saturating_sub(first_node.block.header.timestamp)
+            .clamp((expected_span / MAX_ADJUSTMENT_FACTOR) as u128,
+                   (expected_span * MAX_ADJUSTMENT_FACTOR) as u128) as u64;
+
+        // Scale the target by the ratio, never exceeding the easiest target
+        let max_target = U256::from(self.params.max_target);
+        let target = U256::from(parent_difficulty)
+            .mul_div(actual_span, expected_span)
+            .unwrap_or(max_target);
+        target.min(max_target).into()
+    }
+
+    // Median timestamp of the last MEDIAN_TIME_SPAN blocks ending at `parent_node`
+    fn median_time_past(&self, parent_node: &BlockNode) -> u128 {
+        let mut timestamps = Vec::with_capacity(MEDIAN_TIME_SPAN);
+        let mut node = parent_node;
+        loop {
+            timestamps.push(node.block.header.timestamp);
+            if timestamps.len() == MEDIAN_TIME_SPAN || node.height == 0 { break; }
+            node = &self.map[&node.block.get_parent()];
+        }
+        timestamps.sort_unstable();
+        timestamps[timestamps.len() / 2]
+    }
+
+    /// Get the consensus parameters of the blockchain
+    pub fn params(&self) -> &ChainParams {
+        &self.params
+    }
 
-        Ok(())    // Successfully inserted block
+    /// Get the most recently rejected blocks and why they were rejected, oldest first
+    pub fn rejected_blocks(&self) -> Vec<(H256, BlockError)> {
+        self.rejected.iter().cloned().collect()
     }
 
-    /// Get the last block's hash of the longest chain
+    /// Get the last block's hash of the chain with the most work
     pub fn tip(&self) -> H256 {
-        return self.tip;
+        self.tip
     }
 
     /// Get a desired block from the blockchain
     pub fn get_block(&self, blockhash: &H256) -> Result<&Block, &'static str> {
         match self.map.get(blockhash){
             Some(node) => {
-                return Ok(&node.block);     // block exists in hashmap
+                Ok(&node.block)// block exists in hashmap
             }
             None => {
-                return Err("Block does not exist in blockchain.");   // block not found
+                Err("Block does not exist in blockchain.")// block not found
             }
         }
     }
 
+    /// Get the earliest timestamp a block whose parent is `parent_hash` may carry, just after
+    /// the median timestamp of the last blocks
+    pub fn min_timestamp(&self, parent_hash: &H256) -> Result<u128, &'static str> {
+        match self.map.get(parent_hash) {
+            Some(node) => Ok(self.median_time_past(node) + 1),
+            None => Err("Block does not exist in blockchain."),
+        }
+    }
+
+    /// Get a desired block's height
+    pub fn get_height(&self, blockhash: &H256) -> Result<u64, &'static str> {
+        match self.map.get(blockhash) {
+            Some(node) => Ok(node.height),
+            None => Err("Block does not exist in blockchain."),
+        }
+    }
+
     /// Get a desired block's state
     pub fn get_state(&self, blockhash: &H256) -> Result<&State, &'static str> {
         match self.map.get(blockhash){
             Some(node) => {
-                return Ok(&node.state);     // block exists in hashmap
+                Ok(&node.state)// block exists in hashmap
             }
             None => {
-                return Err("Block does not exist in blockchain.");   // block not found
+                Err("Block does not exist in blockchain.")// block not found
+            }
+        }
+    }
+
+    /// Prove the account at `address`, or its absence, against the state root of a block
+    pub fn account_proof(&self, blockhash: &H256, address: &Address) -> Result<AccountProof, &'static str> {
+        let node = match self.map.get(blockhash) {
+            Some(node) => node,
+            None => return Err("Block does not exist in blockchain."),
+        };
+        Ok(AccountProof {
+            header: node.block.header.clone(),
+            address: *address,
+            account: node.state.get(address),
+            proof: node.tree.prove(address)
+        })
+    }
+
+    /// Find a transaction in the longest chain and prove its inclusion under the merkle root
+    /// of the block holding it
+    pub fn transaction_proof(&self, txn_hash: &H256) -> Option<TransactionProof> {
+        let (block_hash, position) = self.locate_transaction(txn_hash)?;
+        let block = &self.map[&block_hash].block;
+        let index = position + 1;    // the coinbase is leaf 0
+        Some(TransactionProof {
+            header: block.header.clone(),
+            index,
+            leaf_count: block.content.transactions.len() + 1,
+            proof: block.content.merkle_tree().proof(index)
+        })
+    }
+
+    /// Find the block and position of a transaction in the longest chain, from the transaction
+    /// index if it is enabled, or else by scanning the chain from the tip down
+    pub fn locate_transaction(&self, txn_hash: &H256) -> Option<(H256, usize)> {
+        if let Some(txindex) = self.txindex.as_ref() {
+            return txindex.get(txn_hash);
+        }
+
+        let mut cur_block_hash = self.tip;
+        loop {
+            let node = &self.map[&cur_block_hash];
+            let transactions = &node.block.content.transactions;
+            if let Some(position) = transactions.iter().position(|txn| txn.hash() == *txn_hash) {
+                return Some((cur_block_hash, position));
+            }
+            if node.height == 0 {
+                return None;
+            }
+            cur_block_hash = node.block.get_parent();
+        }
+    }
+
+    /// Find the block and position of the latest `limit` transactions sent or received by
+    /// `address` in the longest chain, newest first
+    pub fn recent_transactions(&self, address: &Address, limit: usize) -> Vec<(H256, usize)> {
+        if let Some(txindex) = self.txindex.as_ref() {
+            return txindex.address_transactions(address).iter().rev()
+                .take(limit)
+                .filter_map(|txn_hash| txindex.get(txn_hash))
+                .collect();
+        }
+
+        let mut found = Vec::new();
+        let mut cur_block_hash = self.tip;
+        loop {
+            let node = &self.map[&cur_block_hash];
+            for (position, txn) in node.block.content.transactions.iter().enumerate().rev() {
+                if found.len() == limit {
+                    return found;
+                }
+                let sender = Address::from_public_key_bytes(&txn.public_key);
+                if sender == *address || txn.transaction.receiver == *address {
+                    found.push((cur_block_hash, position));
+                }
+            }
+            if node.height == 0 {
+                return found;
             }
+            cur_block_hash = node.block.get_parent();
         }
     }
 
+    /// Get the hash of the block at `height` in the longest chain
+    pub fn block_at_height(&self, height: u64) -> Option<H256> {
+        let mut cur_block_hash = self.tip;
+        loop {
+            let node = &self.map[&cur_block_hash];
+            if node.height == height {
+                return Some(cur_block_hash);
+            }
+            if node.height < height {
+                return None;
+            }
+            cur_block_hash = node.block.get_parent();
+        }
+    }
+
+    /// Number of blocks of the longest chain from a block up to the tip, counting the block
+    /// itself, or 0 if the block is not in the longest chain
+    pub fn confirmations(&self, blockhash: &H256) -> u64 {
+        let height = match self.map.get(blockhash) {
+            Some(node) => node.height,
+            None => return 0,
+        };
+        match self.block_at_height(height) {
+            Some(hash) if hash == *blockhash => self.map[&self.tip].height - height + 1,
+            _ => 0,
+        }
+    }
+
+    /// Compute the state root a block with the given content on top of `parent_hash` must commit to
+    pub fn state_root_after(&self, parent_hash: &H256, content: &Content) -> Result<H256, BlockError> {
+        let parent_node = match self.map.get(parent_hash) {
+            Some(node) => node,
+            None => return Err(BlockError::Orphan { parent: *parent_hash }),
+        };
+        let state = next_state(&self.params, &parent_node.state, content, parent_node.height + 1)?;
+        Ok(parent_node.tree.update(state.changes()).root())
+    }
+
     /// Get all blocks' hashes of the longest chain, ordered from genesis to the tip
     pub fn all_blocks_in_longest_chain(&self) -> Vec<H256> {
         let mut longest_chain: Vec<H256> = Vec::new();
@@ -209,30 +714,32 @@ impl Blockchain {
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
 
 #[cfg(test)]
-mod tests {
+pub mod tests {
     use super::*;
-    use crate::types::block::generate_random_block;
-    use crate::types::hash::Hashable;
+    use super::store::tests::TempDir;
+    use crate::types::block::{generate_random_block, solve_block};
+    use crate::types::address::generate_random_address;
+    use crate::types::hash::{generate_random_hash, Hashable};
+    use crate::types::sparse_merkle::verify_account;
 
     #[test]
     fn insert_one() {
-        let mut blockchain = Blockchain::new();
+        let mut blockchain = Blockchain::with_params(test_params());
         let genesis_hash = blockchain.tip();
-        let block = generate_random_block(&genesis_hash);
+        let block = generate_random_block(&blockchain, &genesis_hash);
         let _ = blockchain.insert(&block);
         assert_eq!(blockchain.tip(), block.hash());
     }
 
     #[test]
     fn insert_three() {
-        let mut blockchain = Blockchain::new();
+        let mut blockchain = Blockchain::with_params(test_params());
         let genesis_hash = blockchain.tip();
-        let block1 = generate_random_block(&genesis_hash);
-        let block2 = generate_random_block(&block1.hash());
-        let block3 = generate_random_block(&block2.hash());
-
+        let block1 = generate_random_block(&blockchain, &genesis_hash);
         let _ = blockchain.insert(&block1);
+        let block2 = generate_random_block(&blockchain, &block1.hash());
         let _ = blockchain.insert(&block2);
+        let block3 = generate_random_block(&blockchain, &block2.hash());
         let _ = blockchain.insert(&block3);
         
         let chain = blockchain.all_blocks_in_longest_chain();
@@ -256,16 +763,15 @@ mod tests {
 
     #[test]
     fn insert_four_with_fork() {
-        let mut blockchain = Blockchain::new();
+        let mut blockchain = Blockchain::with_params(test_params());
         let genesis_hash = blockchain.tip();
-        let block1 = generate_random_block(&genesis_hash);
-        let block2 = generate_random_block(&block1.hash());
-        let block3 = generate_random_block(&block1.hash());
-        let block4 = generate_random_block(&block3.hash());
-        
+        let block1 = generate_random_block(&blockchain, &genesis_hash);
         let _ = blockchain.insert(&block1);
+        let block2 = generate_random_block(&blockchain, &block1.hash());
         let _ = blockchain.insert(&block2);
+        let block3 = generate_random_block(&blockchain, &block1.hash());
         let _ = blockchain.insert(&block3);
+        let block4 = generate_random_block(&blockchain, &block3.hash());
         let _ = blockchain.insert(&block4);
         
         let chain = blockchain.all_blocks_in_longest_chain();
@@ -291,14 +797,11 @@ mod tests {
     #[test]
     fn insert_six_with_err() {
         // This test was adapted from an Ed post by another student.
-        let mut blockchain = Blockchain::new();
+        let mut blockchain = Blockchain::with_params(test_params());
         let genesis_hash = blockchain.tip();
-        let block1 = generate_random_block(&genesis_hash);
-        let block2 = generate_random_block(&genesis_hash);
-        let block3 = generate_random_block(&genesis_hash.hash().hash());
-        let block4 = generate_random_block(&block1.hash());
-        let block5 = generate_random_block(&block2.hash());
-        let block6 = generate_random_block(&block5.hash());
+        let block1 = generate_random_block(&blockchain, &genesis_hash);
+        let block2 = generate_random_block(&blockchain, &genesis_hash);
+        let block3 = orphan_block(&blockchain, &genesis_hash.hash().hash());
 
         //      genesis
         //        / \
@@ -316,29 +819,28 @@ mod tests {
 
         let c = blockchain.insert(&block3);
         let d = blockchain.insert(&block2);
+        let block4 = generate_random_block(&blockchain, &block1.hash());
         let e = blockchain.insert(&block4);
         assert_eq!(blockchain.tip(), block4.hash());
 
+        let block5 = generate_random_block(&blockchain, &block2.hash());
         let f = blockchain.insert(&block5); 
         assert_eq!(blockchain.tip(), block4.hash());
         
+        let block6 = generate_random_block(&blockchain, &block5.hash());
         let g = blockchain.insert(&block6);
         assert_eq!(blockchain.tip(), block6.hash());
         
-        assert!(!a.is_err());   // Ok
-        assert!(!b.is_err());   // Ok (forked chain)
+        assert!(a.is_ok());   // Ok
+        assert!(b.is_ok());   // Ok (forked chain)
         assert!(c.is_err());    // Err (parent does not exist)
         assert!(d.is_err());    // Err (duplicate block)
-        assert!(!e.is_err());   // Ok (new tip)
-        assert!(!f.is_err());   // Ok
-        assert!(!g.is_err());   // Ok (new tip)
+        assert!(e.is_ok());   // Ok (new tip)
+        assert!(f.is_ok());   // Ok
+        assert!(g.is_ok());   // Ok (new tip)
 
         // Check longest chain
-        let mut hash_vec = Vec::new();
-        hash_vec.push(genesis_hash);
-        hash_vec.push(block2.hash());
-        hash_vec.push(block5.hash());
-        hash_vec.push(block6.hash());
+        let hash_vec = vec![genesis_hash, block2.hash(), block5.hash(), block6.hash()];
         assert_eq!(blockchain.all_blocks_in_longest_chain(), hash_vec);
 
         // Check if height values are correct
@@ -349,6 +851,686 @@ mod tests {
         assert_eq!(blockchain.map.get(&block5.hash()).unwrap().height, 2);
         assert_eq!(blockchain.map.get(&block6.hash()).unwrap().height, 3);
     }
+
+    /// Build a block on `parent` with the given timestamp and the difficulty the chain expects
+    pub fn child_block(blockchain: &Blockchain, parent: &H256, timestamp: u128) -> Block {
+        let mut block = generate_random_block(blockchain, parent);
+        block.header.timestamp = timestamp;
+        solve_block(&mut block);
+        block
+    }
+
+    /// Build a block like generate_random_block on top of `parent`, which is not in the chain
+    pub fn orphan_block(blockchain: &Blockchain, parent: &H256) -> Block {
+        let mut block = generate_random_block(blockchain, &blockchain.tip());
+        block.header.parent = *parent;
+        solve_block(&mut block);
+        block
+    }
+
+    /// Build a block like child_block, carrying the given transactions and collecting their fees
+    pub fn child_block_with(blockchain: &Blockchain, parent: &H256, timestamp: u128,
+                            transactions: Vec<SignedTransaction>) -> Block {
+        let mut block = child_block(blockchain, parent, timestamp);
+        block.content.coinbase.value += transactions.iter().map(|txn| txn.transaction.fee).sum::<u128>();
+        block.content.transactions = transactions;
+        seal_block(blockchain, &mut block);
+        block
+    }
+
+    /// Commit the header to the block's content and to the state it leaves, then solve it.
+    /// The state root is left zero if the content is invalid on its parent.
+    pub fn seal_block(blockchain: &Blockchain, block: &mut Block) {
+        block.header.merkle_root = block.content.merkle_root();
+        block.header.state_root = blockchain.state_root_after(&block.get_parent(), &block.content)
+            .unwrap_or_default();
+        solve_block(block);
+    }
+
+    /// Sign a transaction from one of the genesis accounts
+    pub fn signed_transaction(seed: u8, account_nonce: u128, receiver: Address, value: u128, fee: u128) -> SignedTransaction {
+        let key = Ed25519KeyPair::from_seed_unchecked(&[seed; 32]).unwrap();
+        let transaction = transaction::Transaction { account_nonce, receiver, value, fee };
+        let signature = transaction::sign(&transaction, &key).as_ref().to_vec();
+        SignedTransaction { transaction, signature, public_key: key.public_key().as_ref().to_vec() }
+    }
+
+    pub fn genesis_address(seed: u8) -> Address {
+        let key = Ed25519KeyPair::from_seed_unchecked(&[seed; 32]).unwrap();
+        Address::from_public_key_bytes(key.public_key().as_ref())
+    }
+
+    // Extend the chain at `parent` by `count` blocks spaced `spacing` ms apart, returning the new tip
+    fn extend_chain(blockchain: &mut Blockchain, parent: &H256, count: u64, start: u128, spacing: u128) -> H256 {
+        let mut parent = *parent;
+        for i in 0..count {
+            let block = child_block(blockchain, &parent, start + i as u128 * spacing);
+            assert!(blockchain.insert(&block).is_ok());
+            parent = block.hash();
+        }
+        parent
+    }
+
+    fn scaled_max_target(mul: u64, div: u64) -> H256 {
+        U256::from(test_params().max_target).mul_div(mul, div).unwrap().into()
+    }
+
+    #[test]
+    fn main_chain_requires_max_target() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let max_target = H256::from(MAX_TARGET);
+        assert_eq!(blockchain.get_block(&genesis_hash).unwrap().get_difficulty(), max_target);
+        assert_eq!(blockchain.next_difficulty(&genesis_hash).unwrap(), max_target);
+
+        // A block only meeting the easier target of the tests is rejected either way
+        let easy = Blockchain::with_params(test_params());
+        let mut block = generate_random_block(&easy, &easy.tip());
+        block.header.parent = genesis_hash;
+        solve_block(&mut block);
+        assert_eq!(blockchain.insert(&block), Err(BlockError::InvalidDifficulty {
+            expected: max_target,
+            found: test_params().max_target
+        }));
+
+        block.header.difficulty = max_target;
+        while block.hash() <= max_target || block.hash() > test_params().max_target {
+            block.header.nonce = block.header.nonce.wrapping_add(1);
+        }
+        assert_eq!(blockchain.insert(&block), Err(BlockError::InvalidProofOfWork));
+    }
+
+    #[test]
+    fn difficulty_kept_within_interval() {
+        let mut blockchain = Blockchain::with_params(test_params());
+        let genesis_hash = blockchain.tip();
+        let tip = extend_chain(&mut blockchain, &genesis_hash, DIFFICULTY_ADJUSTMENT_INTERVAL - 2, 1_000_000, 1);
+        assert_eq!(blockchain.next_difficulty(&tip).unwrap(), test_params().max_target);
+
+        // A block that does not carry its parent's difficulty is rejected
+        let mut block = child_block(&blockchain, &tip, 2_000_000);
+        block.header.difficulty = scaled_max_target(1, 2);
+        solve_block(&mut block);
+        assert!(blockchain.insert(&block).is_err());
+    }
+
+    #[test]
+    fn difficulty_retargets_from_timestamps() {
+        let mut blockchain = Blockchain::with_params(test_params());
+        let genesis_hash = blockchain.tip();
+        let interval = DIFFICULTY_ADJUSTMENT_INTERVAL;
+        let spacing = TARGET_BLOCK_TIME as u128;
+
+        // The first interval is measured from the genesis timestamp, so it stays at the easiest target
+        let tip = extend_chain(&mut blockchain, &genesis_hash, interval - 1, 1_000_000, spacing);
+        assert_eq!(blockchain.next_difficulty(&tip).unwrap(), test_params().max_target);
+
+        // Blocks twice as fast as desired halve the target
+        let tip = extend_chain(&mut blockchain, &tip, interval, 2_000_000, spacing / 2);
+        assert_eq!(blockchain.next_difficulty(&tip).unwrap(), scaled_max_target(1, 2));
+
+        // A wrongly retargeted block is rejected
+        let mut block = child_block(&blockchain, &tip, 3_000_000);
+        block.header.difficulty = test_params().max_target;
+        solve_block(&mut block);
+        assert!(blockchain.insert(&block).is_err());
+
+        // Blocks twice as slow as desired double it again
+        let tip = extend_chain(&mut blockchain, &tip, interval, 3_000_000, spacing * 2);
+        assert_eq!(blockchain.next_difficulty(&tip).unwrap(), test_params().max_target);
+
+        // Blocks even slower cannot go past the easiest target
+        let tip = extend_chain(&mut blockchain, &tip, interval, 4_000_000, spacing * 3);
+        assert_eq!(blockchain.next_difficulty(&tip).unwrap(), test_params().max_target);
+    }
+
+    #[test]
+    fn difficulty_adjustment_is_clamped() {
+        let mut blockchain = Blockchain::with_params(test_params());
+        let genesis_hash = blockchain.tip();
+        let interval = DIFFICULTY_ADJUSTMENT_INTERVAL;
+        let tip = extend_chain(&mut blockchain, &genesis_hash, interval - 1, 1_000_000, TARGET_BLOCK_TIME as u128);
+
+        // An interval mined almost instantly only raises the difficulty by MAX_ADJUSTMENT_FACTOR
+        let tip = extend_chain(&mut blockchain, &tip, interval, 2_000_000, 1);
+        assert_eq!(blockchain.next_difficulty(&tip).unwrap(), scaled_max_target(1, MAX_ADJUSTMENT_FACTOR));
+    }
+
+    #[test]
+    fn heavier_fork_beats_longer_fork() {
+        let mut blockchain = Blockchain::with_params(test_params());
+        let genesis_hash = blockchain.tip();
+        let interval = DIFFICULTY_ADJUSTMENT_INTERVAL;
+        let spacing = TARGET_BLOCK_TIME as u128;
+        let fork_point = extend_chain(&mut blockchain, &genesis_hash, interval - 1, 1_000_000, spacing);
+
+        //             fork_point
+        //              /      \
+        //   interval blocks   interval blocks
+        //   at target pace    mined 1ms apart
+        //          |              |
+        //      a1 (1x)        b1 (4x work)
+        //          |
+        //      a2 (1x)
+        //          |
+        //      a3 (1x)
+
+        let a = extend_chain(&mut blockchain, &fork_point, interval, 2_000_000, spacing);
+        let b = extend_chain(&mut blockchain, &fork_point, interval, 2_000_000, 1);
+        assert_eq!(blockchain.tip(), a);    // equal work, first seen wins
+        let a3 = extend_chain(&mut blockchain, &a, 3, 3_000_000, spacing);
+        assert_eq!(blockchain.tip(), a3);
+
+        // A single block on the harder fork outweighs the three easy ones
+        let b1 = child_block(&blockchain, &b, 3_000_000);
+        assert_eq!(b1.get_difficulty(), scaled_max_target(1, MAX_ADJUSTMENT_FACTOR));
+        assert!(blockchain.insert(&b1).is_ok());
+        assert_eq!(blockchain.tip(), b1.hash());
+        assert!(blockchain.map[&a3].height > blockchain.map[&b1.hash()].height);
+        assert_eq!(blockchain.all_blocks_in_longest_chain().last(), Some(&b1.hash()));
+
+        // Four easy blocks fall just short of one four times harder, since work rounds down
+        let a4 = extend_chain(&mut blockchain, &a3, 1, 4_000_000, spacing);
+        assert!(blockchain.map[&a4].chainwork < blockchain.map[&b1.hash()].chainwork);
+        assert_eq!(blockchain.tip(), b1.hash());
+
+        // Exceeding it does
+        let a5 = extend_chain(&mut blockchain, &a4, 1, 5_000_000, spacing);
+        assert_eq!(blockchain.tip(), a5);
+    }
+
+    #[test]
+    fn insert_rejects_invalid_headers() {
+        let mut blockchain = Blockchain::with_params(test_params());
+        let genesis_hash = blockchain.tip();
+        let tip = extend_chain(&mut blockchain, &genesis_hash, 3, 1_000_000, 1000);
+
+        // Hash above the difficulty
+        let mut block = child_block(&blockchain, &tip, 2_000_000);
+        while block.hash() <= block.get_difficulty() {
+            block.header.nonce = block.header.nonce.wrapping_add(1);
+        }
+        assert_eq!(blockchain.insert(&block), Err(BlockError::InvalidProofOfWork));
+
+        // Merkle root that does not match the transactions
+        let mut block = child_block(&blockchain, &tip, 2_000_000);
+        block.header.merkle_root = generate_random_hash();
+        solve_block(&mut block);
+        assert_eq!(blockchain.insert(&block), Err(BlockError::InvalidMerkleRoot));
+
+        // Timestamp not after the median of the last blocks (1_001_000)
+        let block = child_block(&blockchain, &tip, 1_001_000);
+        assert_eq!(blockchain.insert(&block),
+                   Err(BlockError::TimestampTooOld { median_time_past: 1_001_000, found: 1_001_000 }));
+        assert_eq!(blockchain.min_timestamp(&tip), Ok(1_001_001));
+
+        // Timestamp too far in the future
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
+        let block = child_block(&blockchain, &tip, now + 2 * MAX_FUTURE_BLOCK_TIME);
+        assert!(matches!(blockchain.insert(&block), Err(BlockError::TimestampTooNew { .. })));
+
+        // Block above the size limit
+        let mut block = child_block(&blockchain, &tip, 2_000_000);
+        let txn_size = bincode::serialized_size(&SignedTransaction::default()).unwrap() as usize;
+        block.content.transactions = vec![SignedTransaction::default(); MAX_BLOCK_SIZE / txn_size + 1];
+        seal_block(&blockchain, &mut block);
+        assert!(matches!(blockchain.insert(&block), Err(BlockError::TooLarge { .. })));
+
+        // Every rejection is recorded with its reason
+        let rejected = blockchain.rejected_blocks();
+        assert_eq!(rejected.len(), 5);
+        assert_eq!(rejected[0].1, BlockError::InvalidProofOfWork);
+        assert_eq!(rejected[4].0, block.hash());
+
+        assert_eq!(blockchain.tip(), tip);
+        let block = child_block(&blockchain, &tip, 1_001_001);
+        assert!(blockchain.insert(&block).is_ok());
+    }
+
+    #[test]
+    fn insert_reports_invalid_transaction() {
+        let mut blockchain = Blockchain::with_params(test_params());
+        let genesis_hash = blockchain.tip();
+        let valid = signed_transaction(0, 1, genesis_address(1), 10, 0);
+
+        // Tampered value breaks the signature of the second transaction
+        let mut tampered = signed_transaction(1, 1, genesis_address(2), 0, 0);
+        tampered.transaction.value = 1;
+        let block = child_block_with(&blockchain, &genesis_hash, 1_000_000, vec![valid.clone(), tampered]);
+        let error = blockchain.insert(&block).unwrap_err();
+        assert_eq!(error, BlockError::InvalidTransaction { index: 1, error: TransactionError::InvalidSignature });
+        assert_eq!(error.transaction_index(), Some(1));
+
+        let wrong_nonce = signed_transaction(0, 2, genesis_address(1), 10, 0);
+        let block = child_block_with(&blockchain, &genesis_hash, 1_000_000, vec![wrong_nonce]);
+        assert_eq!(blockchain.insert(&block), Err(BlockError::InvalidTransaction {
+            index: 0,
+            error: TransactionError::InvalidNonce { expected: 1, found: 2 }
+        }));
+
+        let overspend = signed_transaction(0, 1, genesis_address(1), 10001, 0);
+        let block = child_block_with(&blockchain, &genesis_hash, 1_000_000, vec![overspend]);
+        assert_eq!(blockchain.insert(&block), Err(BlockError::InvalidTransaction {
+            index: 0,
+            error: TransactionError::InsufficientBalance { balance: 10000, required: 10001 }
+        }));
+
+        let unknown = SignedTransaction { public_key: vec![7; 32], ..valid.clone() };
+        let block = child_block_with(&blockchain, &genesis_hash, 1_000_000, vec![unknown]);
+        assert!(matches!(blockchain.insert(&block),
+                         Err(BlockError::InvalidTransaction { index: 0, error: TransactionError::InvalidSignature })));
+
+        let block = child_block_with(&blockchain, &genesis_hash, 1_000_000, vec![valid]);
+        assert!(blockchain.insert(&block).is_ok());
+        assert_eq!(blockchain.insert(&block), Err(BlockError::Duplicate));
+        let orphan = orphan_block(&blockchain, &generate_random_hash());
+        assert_eq!(blockchain.insert(&orphan), Err(BlockError::Orphan { parent: orphan.get_parent() }));
+    }
+
+    #[test]
+    fn insert_reports_chain_update() {
+        let mut blockchain = Blockchain::with_params(test_params());
+        let genesis_hash = blockchain.tip();
+        let block1 = generate_random_block(&blockchain, &genesis_hash);
+        assert_eq!(blockchain.insert(&block1), Ok(ChainUpdate { disconnected: vec![], connected: vec![block1.hash()] }));
+        let block2 = generate_random_block(&blockchain, &block1.hash());
+        assert_eq!(blockchain.insert(&block2), Ok(ChainUpdate { disconnected: vec![], connected: vec![block2.hash()] }));
+
+        // Blocks on a fork with no more work leave the tip alone
+        let block3 = generate_random_block(&blockchain, &genesis_hash);
+        assert!(blockchain.insert(&block3).unwrap().is_empty());
+        let block4 = generate_random_block(&blockchain, &block3.hash());
+        assert!(blockchain.insert(&block4).unwrap().is_empty());
+
+        // Overtaking fork swaps the chains back to the common ancestor
+        let block5 = generate_random_block(&blockchain, &block4.hash());
+        assert_eq!(blockchain.insert(&block5), Ok(ChainUpdate {
+            disconnected: vec![block2.hash(), block1.hash()],
+            connected: vec![block3.hash(), block4.hash(), block5.hash()]
+        }));
+    }
+
+    #[test]
+    fn block_subsidy_halves() {
+        let params = ChainParams::default();
+        assert_eq!(params.block_subsidy(1), INITIAL_BLOCK_SUBSIDY);
+        assert_eq!(params.block_subsidy(SUBSIDY_HALVING_INTERVAL - 1), INITIAL_BLOCK_SUBSIDY);
+        assert_eq!(params.block_subsidy(SUBSIDY_HALVING_INTERVAL), INITIAL_BLOCK_SUBSIDY / 2);
+        assert_eq!(params.block_subsidy(3 * SUBSIDY_HALVING_INTERVAL), INITIAL_BLOCK_SUBSIDY / 8);
+        assert_eq!(params.block_subsidy(200 * SUBSIDY_HALVING_INTERVAL), 0);
+
+        let never_halves = ChainParams { subsidy_halving_interval: 0, ..ChainParams::default() };
+        assert_eq!(never_halves.block_subsidy(1000 * SUBSIDY_HALVING_INTERVAL), INITIAL_BLOCK_SUBSIDY);
+    }
+
+    #[test]
+    fn configured_subsidy_is_enforced() {
+        let params = ChainParams { initial_block_subsidy: 50, subsidy_halving_interval: 2, ..test_params() };
+        let mut blockchain = Blockchain::with_params(params);
+        let genesis_hash = blockchain.tip();
+
+        // Block 1 mints the configured subsidy, not the main chain's
+        let block1 = generate_random_block(&blockchain, &genesis_hash);
+        assert_eq!(block1.content.coinbase.value, 50);
+        let mut block = block1.clone();
+        block.content.coinbase.value = INITIAL_BLOCK_SUBSIDY;
+        seal_block(&blockchain, &mut block);
+        assert_eq!(blockchain.insert(&block), Err(BlockError::InvalidCoinbaseValue {
+            expected: 50,
+            found: INITIAL_BLOCK_SUBSIDY
+        }));
+        assert!(blockchain.insert(&block1).is_ok());
+
+        // and block 2 half of it
+        let block2 = generate_random_block(&blockchain, &block1.hash());
+        assert_eq!(block2.content.coinbase.value, 25);
+        assert!(blockchain.insert(&block2).is_ok());
+    }
+
+    #[test]
+    fn coinbase_pays_miner() {
+        let mut blockchain = Blockchain::with_params(test_params());
+        let genesis_hash = blockchain.tip();
+        let miner = generate_random_address();
+
+        // Coinbase must mint exactly the subsidy at the block's height
+        let mut block = child_block(&blockchain, &genesis_hash, 1_000_000);
+        block.content.coinbase.value += 1;
+        seal_block(&blockchain, &mut block);
+        assert_eq!(blockchain.insert(&block), Err(BlockError::InvalidCoinbaseValue {
+            expected: INITIAL_BLOCK_SUBSIDY,
+            found: INITIAL_BLOCK_SUBSIDY + 1
+        }));
+
+        let mut block = child_block(&blockchain, &genesis_hash, 1_000_000);
+        block.content.coinbase.height = 2;
+        seal_block(&blockchain, &mut block);
+        assert_eq!(blockchain.insert(&block), Err(BlockError::InvalidCoinbaseHeight { expected: 1, found: 2 }));
+
+        // Coinbase is committed by the merkle root
+        let mut block = child_block(&blockchain, &genesis_hash, 1_000_000);
+        block.content.coinbase.receiver = miner;
+        solve_block(&mut block);
+        assert_eq!(blockchain.insert(&block), Err(BlockError::InvalidMerkleRoot));
+
+        // Reward is credited to a new account, and spending it in the next block pays the receiver
+        let mut block1 = child_block(&blockchain, &genesis_hash, 1_000_000);
+        block1.content.coinbase.receiver = miner;
+        seal_block(&blockchain, &mut block1);
+        assert!(blockchain.insert(&block1).is_ok());
+        let state = blockchain.get_state(&block1.hash()).unwrap();
+        assert_eq!(state.get(&miner).unwrap(), (0, INITIAL_BLOCK_SUBSIDY));
+
+        let receiver = generate_random_address();
+        let txns = vec![
+            signed_transaction(0, 1, receiver, 100, 0),
+            signed_transaction(0, 2, receiver, 50, 0),
+        ];
+        let block2 = child_block_with(&blockchain, &block1.hash(), 1_001_000, txns);
+        assert!(blockchain.insert(&block2).is_ok());
+        let state = blockchain.get_state(&block2.hash()).unwrap();
+        assert_eq!(state.get(&genesis_address(0)).unwrap(), (2, 9850));
+        assert_eq!(state.get(&receiver).unwrap(), (0, 150));
+        assert_eq!(state.get(&miner).unwrap(), (0, INITIAL_BLOCK_SUBSIDY));
+
+        // Block state only stores the sender, the receiver and the coinbase receiver
+        assert_eq!(state.changes().len(), 3);
+        assert_eq!(state.get(&genesis_address(1)), blockchain.get_state(&genesis_hash).unwrap().get(&genesis_address(1)));
+    }
+
+    #[test]
+    fn header_commits_to_state() {
+        let mut blockchain = Blockchain::with_params(test_params());
+        let genesis_hash = blockchain.tip();
+        let genesis_root = blockchain.map[&genesis_hash].block.header.state_root;
+        assert_eq!(genesis_root, SparseMerkleTree::new(&genesis_state()).root());
+
+        // State root of a different state is rejected
+        let receiver = generate_random_address();
+        let txns = vec![signed_transaction(0, 1, receiver, 100, 1)];
+        let mut block = child_block_with(&blockchain, &genesis_hash, 1_000_000, txns);
+        let state_root = block.header.state_root;
+        block.header.state_root = genesis_root;
+        solve_block(&mut block);
+        assert_eq!(blockchain.insert(&block), Err(BlockError::InvalidStateRoot {
+            expected: state_root,
+            found: genesis_root
+        }));
+
+        block.header.state_root = state_root;
+        solve_block(&mut block);
+        assert!(blockchain.insert(&block).is_ok());
+
+        // The committed root proves the receiver's new balance
+        let state = blockchain.get_state(&block.hash()).unwrap();
+        assert_eq!(blockchain.map[&block.hash()].tree.root(), SparseMerkleTree::new(state).root());
+        let proof = blockchain.map[&block.hash()].tree.prove(&receiver);
+        assert!(crate::types::sparse_merkle::verify(&state_root, &receiver, Some((0, 100)), &proof));
+    }
+
+    #[test]
+    fn account_proof_verifies() {
+        let mut blockchain = Blockchain::with_params(test_params());
+        let genesis_hash = blockchain.tip();
+        let receiver = generate_random_address();
+        let txns = vec![signed_transaction(0, 1, receiver, 100, 1)];
+        let block = child_block_with(&blockchain, &genesis_hash, 1_000_000, txns);
+        assert!(blockchain.insert(&block).is_ok());
+
+        // Proofs survive a round trip through the API's JSON
+        let proof = blockchain.account_proof(&block.hash(), &receiver).unwrap();
+        let json = serde_json::to_string(&proof).unwrap();
+        let proof: AccountProof = serde_json::from_str(&json).unwrap();
+        assert_eq!(proof.header.hash(), block.hash());
+        assert_eq!(proof.account, Some((0, 100)));
+        assert!(verify_account(&proof));
+
+        // The receiver does not exist before the block
+        let proof = blockchain.account_proof(&genesis_hash, &receiver).unwrap();
+        assert_eq!(proof.account, None);
+        assert!(verify_account(&proof));
+
+        // A forged balance or header fails
+        let mut proof = blockchain.account_proof(&block.hash(), &receiver).unwrap();
+        proof.account = Some((0, 1000));
+        assert!(!verify_account(&proof));
+        let mut proof = blockchain.account_proof(&block.hash(), &receiver).unwrap();
+        proof.header.state_root = generate_random_hash();
+        assert!(!verify_account(&proof));
+
+        assert!(blockchain.account_proof(&generate_random_hash(), &receiver).is_err());
+    }
+
+    #[test]
+    fn transaction_proof_verifies() {
+        let mut blockchain = Blockchain::with_params(test_params());
+        let genesis_hash = blockchain.tip();
+        let receiver = generate_random_address();
+        let txns: Vec<SignedTransaction> = (1..=3)
+            .map(|nonce| signed_transaction(0, nonce, receiver, 10, 1))
+            .collect();
+        let block1 = child_block_with(&blockchain, &genesis_hash, 1_000_000, txns.clone());
+        assert!(blockchain.insert(&block1).is_ok());
+        let later = signed_transaction(0, 4, receiver, 10, 1);
+        let block2 = child_block_with(&blockchain, &block1.hash(), 1_001_000, vec![later.clone()]);
+        assert!(blockchain.insert(&block2).is_ok());
+
+        for (position, txn) in txns.iter().chain(std::iter::once(&later)).enumerate() {
+            let proof = blockchain.transaction_proof(&txn.hash()).unwrap();
+            let (block, index) = if position < 3 { (&block1, position + 1) } else { (&block2, 1) };
+            assert_eq!(proof.header.hash(), block.hash());
+            assert_eq!(proof.index, index);
+            assert_eq!(proof.leaf_count, block.content.transactions.len() + 1);
+            assert!(crate::types::merkle::verify(&proof.header.merkle_root, &txn.hash(), &proof.proof,
+                                                 proof.index, proof.leaf_count));
+        }
+
+        // Transactions only in a side chain or nowhere are not found
+        let side = child_block_with(&blockchain, &genesis_hash, 1_000_500,
+                                    vec![signed_transaction(0, 1, receiver, 20, 1)]);
+        assert!(blockchain.insert(&side).is_ok());
+        assert!(blockchain.transaction_proof(&side.content.transactions[0].hash()).is_none());
+        assert!(blockchain.transaction_proof(&generate_random_hash()).is_none());
+    }
+
+    #[test]
+    fn txindex_follows_longest_chain() {
+        let mut blockchain = Blockchain::with_params(test_params());
+        let genesis_hash = blockchain.tip();
+        let receiver = generate_random_address();
+        let first = signed_transaction(0, 1, receiver, 10, 1);
+        let block1 = child_block_with(&blockchain, &genesis_hash, 1_000_000, vec![first.clone()]);
+        assert!(blockchain.insert(&block1).is_ok());
+
+        // Blocks already in the chain are indexed when the index is enabled
+        assert!(blockchain.txindex().is_none());
+        blockchain.enable_txindex();
+        let second = signed_transaction(0, 2, receiver, 10, 1);
+        let back = signed_transaction(1, 1, genesis_address(0), 0, 0);
+        let block2 = child_block_with(&blockchain, &block1.hash(), 1_001_000, vec![second.clone()]);
+        assert!(blockchain.insert(&block2).is_ok());
+
+        let txindex = blockchain.txindex().unwrap();
+        assert_eq!(txindex.get(&first.hash()), Some((block1.hash(), 0)));
+        assert_eq!(txindex.get(&second.hash()), Some((block2.hash(), 0)));
+        assert_eq!(txindex.address_transactions(&receiver), &[first.hash(), second.hash()]);
+        assert_eq!(txindex.address_transactions(&genesis_address(0)), &[first.hash(), second.hash()]);
+        assert!(txindex.address_transactions(&genesis_address(1)).is_empty());
+
+        // A heavier fork replaces block2, so its transaction leaves the index
+        let fork1 = child_block(&blockchain, &block1.hash(), 1_000_500);
+        assert!(blockchain.insert(&fork1).is_ok());
+        let fork2 = child_block_with(&blockchain, &fork1.hash(), 1_002_000, vec![back.clone()]);
+        assert!(blockchain.insert(&fork2).is_ok());
+        assert_eq!(blockchain.tip(), fork2.hash());
+
+        let txindex = blockchain.txindex().unwrap();
+        assert_eq!(txindex.get(&second.hash()), None);
+        assert_eq!(txindex.get(&back.hash()), Some((fork2.hash(), 0)));
+        assert_eq!(txindex.address_transactions(&receiver), &[first.hash()]);
+        assert_eq!(txindex.address_transactions(&genesis_address(0)), &[first.hash(), back.hash()]);
+        assert_eq!(txindex.address_transactions(&genesis_address(1)), &[back.hash()]);
+
+        // Proofs are served from the index
+        let proof = blockchain.transaction_proof(&back.hash()).unwrap();
+        assert_eq!(proof.header.hash(), fork2.hash());
+        assert!(blockchain.transaction_proof(&second.hash()).is_none());
+    }
+
+    #[test]
+    fn explorer_lookups() {
+        let mut blockchain = Blockchain::with_params(test_params());
+        let genesis_hash = blockchain.tip();
+        let receiver = generate_random_address();
+        let first = signed_transaction(0, 1, receiver, 10, 1);
+        let second = signed_transaction(0, 2, receiver, 10, 1);
+        let block1 = child_block_with(&blockchain, &genesis_hash, 1_000_000, vec![first.clone()]);
+        assert!(blockchain.insert(&block1).is_ok());
+        let block2 = child_block_with(&blockchain, &block1.hash(), 1_001_000, vec![second.clone()]);
+        assert!(blockchain.insert(&block2).is_ok());
+        let side = child_block(&blockchain, &genesis_hash, 1_000_500);
+        assert!(blockchain.insert(&side).is_ok());
+
+        assert_eq!(blockchain.block_at_height(0), Some(genesis_hash));
+        assert_eq!(blockchain.block_at_height(2), Some(block2.hash()));
+        assert_eq!(blockchain.block_at_height(3), None);
+        assert_eq!(blockchain.confirmations(&block1.hash()), 2);
+        assert_eq!(blockchain.confirmations(&block2.hash()), 1);
+        assert_eq!(blockchain.confirmations(&side.hash()), 0);
+        assert_eq!(blockchain.locate_transaction(&second.hash()), Some((block2.hash(), 0)));
+
+        // Recent transactions are the same with or without the index
+        let expected = vec![(block2.hash(), 0), (block1.hash(), 0)];
+        assert_eq!(blockchain.recent_transactions(&receiver, 10), expected);
+        assert_eq!(blockchain.recent_transactions(&receiver, 1), expected[..1]);
+        blockchain.enable_txindex();
+        assert_eq!(blockchain.recent_transactions(&receiver, 10), expected);
+        assert_eq!(blockchain.recent_transactions(&receiver, 1), expected[..1]);
+        assert!(blockchain.recent_transactions(&genesis_address(1), 10).is_empty());
+    }
+
+    #[test]
+    fn fees_are_paid_to_miner() {
+        let mut blockchain = Blockchain::with_params(test_params());
+        let genesis_hash = blockchain.tip();
+        let miner = generate_random_address();
+        let receiver = generate_random_address();
+        let txns = vec![
+            signed_transaction(0, 1, receiver, 100, 7),
+            signed_transaction(0, 2, receiver, 50, 3),
+        ];
+
+        // Fee is covered by the signature
+        let mut tampered = txns[0].clone();
+        tampered.transaction.fee = 0;
+        let block = child_block_with(&blockchain, &genesis_hash, 1_000_000, vec![tampered]);
+        assert_eq!(blockchain.insert(&block), Err(BlockError::InvalidTransaction {
+            index: 0,
+            error: TransactionError::InvalidSignature
+        }));
+
+        // Sender must afford the value plus the fee
+        let overspend = signed_transaction(0, 1, receiver, 9995, 6);
+        let block = child_block_with(&blockchain, &genesis_hash, 1_000_000, vec![overspend]);
+        assert_eq!(blockchain.insert(&block), Err(BlockError::InvalidTransaction {
+            index: 0,
+            error: TransactionError::InsufficientBalance { balance: 10000, required: 10001 }
+        }));
+
+        // Coinbase that leaves out the fees is rejected
+        let mut block = child_block_with(&blockchain, &genesis_hash, 1_000_000, txns.clone());
+        block.content.coinbase.value -= 10;
+        seal_block(&blockchain, &mut block);
+        assert_eq!(blockchain.insert(&block), Err(BlockError::InvalidCoinbaseValue {
+            expected: INITIAL_BLOCK_SUBSIDY + 10,
+            found: INITIAL_BLOCK_SUBSIDY
+        }));
+
+        let mut block = child_block_with(&blockchain, &genesis_hash, 1_000_000, txns);
+        block.content.coinbase.receiver = miner;
+        seal_block(&blockchain, &mut block);
+        assert!(blockchain.insert(&block).is_ok());
+        let state = blockchain.get_state(&block.hash()).unwrap();
+        assert_eq!(state.get(&genesis_address(0)).unwrap(), (2, 9840));
+        assert_eq!(state.get(&receiver).unwrap(), (0, 150));
+        assert_eq!(state.get(&miner).unwrap(), (0, INITIAL_BLOCK_SUBSIDY + 10));
+    }
+
+    #[test]
+    fn reopen_restores_chain() {
+        let dir = TempDir::new();
+        let mut blockchain = Blockchain::open(&dir.0, test_params()).unwrap();
+        let genesis_hash = blockchain.tip();
+        let block1 = generate_random_block(&blockchain, &genesis_hash);
+        assert!(blockchain.insert(&block1).is_ok());
+        let block2 = generate_random_block(&blockchain, &block1.hash());
+        assert!(blockchain.insert(&block2).is_ok());
+        let block3 = generate_random_block(&blockchain, &genesis_hash);
+        assert!(blockchain.insert(&block3).is_ok());
+        let chain = blockchain.all_blocks_in_longest_chain();
+        drop(blockchain);
+
+        let blockchain = Blockchain::open(&dir.0, test_params()).unwrap();
+        assert_eq!(blockchain.tip(), block2.hash());
+        assert_eq!(blockchain.all_blocks_in_longest_chain(), chain);
+        assert!(blockchain.get_block(&block3.hash()).is_ok());
+        assert_eq!(blockchain.map.get(&block2.hash()).unwrap().height, 2);
+        assert_eq!(blockchain.map.get(&block3.hash()).unwrap().height, 1);
+    }
+
+    #[test]
+    fn reopen_after_torn_write() {
+        let dir = TempDir::new();
+        let mut blockchain = Blockchain::open(&dir.0, test_params()).unwrap();
+        let genesis_hash = blockchain.tip();
+        let block1 = generate_random_block(&blockchain, &genesis_hash);
+        assert!(blockchain.insert(&block1).is_ok());
+        let block2 = generate_random_block(&blockchain, &block1.hash());
+        assert!(blockchain.insert(&block2).is_ok());
+        drop(blockchain);
+
+        // Killed halfway through writing the tip record for block2
+        let path = dir.0.join("blocks.log");
+        let len = std::fs::metadata(&path).unwrap().len();
+        let file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(len - 3).unwrap();
+        drop(file);
+
+        // The tip is recomputed from the surviving blocks
+        let mut blockchain = Blockchain::open(&dir.0, test_params()).unwrap();
+        assert_eq!(blockchain.tip(), block2.hash());
+
+        // Blocks inserted after recovery are persisted too
+        let block3 = generate_random_block(&blockchain, &block2.hash());
+        assert!(blockchain.insert(&block3).is_ok());
+        drop(blockchain);
+        let blockchain = Blockchain::open(&dir.0, test_params()).unwrap();
+        assert_eq!(blockchain.tip(), block3.hash());
+        assert_eq!(blockchain.all_blocks_in_longest_chain().len(), 4);
+    }
+
+    #[test]
+    fn reopen_fails_on_invalid_stored_block() {
+        let dir = TempDir::new();
+        let mut blockchain = Blockchain::open(&dir.0, test_params()).unwrap();
+        let genesis_hash = blockchain.tip();
+        let block1 = generate_random_block(&blockchain, &genesis_hash);
+        assert!(blockchain.insert(&block1).is_ok());
+        let mut invalid = generate_random_block(&blockchain, &block1.hash());
+        drop(blockchain);
+
+        // A block that does not belong to this chain follows it in the log
+        let (mut store, _) = BlockStore::open(&dir.0).unwrap();
+        invalid.header.merkle_root = generate_random_hash();
+        solve_block(&mut invalid);
+        store.append(&Record::Block { block: Box::new(invalid), height: 2 }).unwrap();
+        drop(store);
+        let len = std::fs::metadata(dir.0.join("blocks.log")).unwrap().len();
+
+        // Opening fails instead of dropping it, and the log is left as it was
+        assert!(Blockchain::open(&dir.0, test_params()).is_err());
+        assert_eq!(std::fs::metadata(dir.0.join("blocks.log")).unwrap().len(), len);
+    }
 }
 
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
\ No newline at end of file
@@ -0,0 +1,344 @@
+use crate::types::{
+    block::Block,
+    hash::H256,
+};
+use serde::{Serialize, Deserialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+// Name of the block log inside the data directory
+const LOG_FILE: &str = "blocks.log";
+
+// Every log starts with [magic][format version (u32 LE)], so that a log whose records were
+// written with another layout is refused instead of being mistaken for a corrupt one
+const MAGIC: [u8; 4] = *b"BLKS";
+const VERSION_SIZE: usize = 4;
+const LOG_HEADER_SIZE: usize = MAGIC.len() + VERSION_SIZE;
+
+/// Version of the layout of stored records; bump it whenever the serialization of a Block
+/// or of anything it holds changes
+pub const FORMAT_VERSION: u32 = 1;
+
+// Every record is framed as [payload length (u32 LE)][checksum][payload]
+const LENGTH_SIZE: usize = 4;
+const CHECKSUM_SIZE: usize = 4;
+const FRAME_HEADER_SIZE: usize = LENGTH_SIZE + CHECKSUM_SIZE;
+
+// A Record is a single entry of the append-only block log
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Record {
+    Block { block: Box<Block>, height: u64 },
+    Tip(H256),
+}
+
+// A BlockStore persists the blocks accepted by the Blockchain in an append-only log
+pub struct BlockStore {
+    file: File,
+    len: u64,    // length of the log up to the last complete record
+}
+
+impl BlockStore {
+    /// Open (or create) the block log inside `dir`, returning the store and every complete
+    /// record in it. A torn record left by a crash mid-write is truncated from the log, but a
+    /// log of another format version or holding a record that cannot be decoded is an error.
+    pub fn open<P: AsRef<Path>>(dir: P) -> io::Result<(Self, Vec<Record>)> {
+        fs::create_dir_all(&dir)?;
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(dir.as_ref().join(LOG_FILE))?;
+
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+
+        // A new log, or one whose header was torn while being created, gets a fresh header
+        let header = log_header();
+        if bytes.len() < LOG_HEADER_SIZE && header.starts_with(&bytes) {
+            file.set_len(0)?;
+            file.seek(SeekFrom::Start(0))?;
+            file.write_all(&header)?;
+            file.sync_all()?;
+            bytes = header.to_vec();
+        }
+        check_log_header(&bytes)?;
+
+        // Read records until the end of the log or the first incomplete/corrupt one
+        let mut records = Vec::new();
+        let mut offset = LOG_HEADER_SIZE;
+        while let Some((record, frame_len)) = decode(&bytes[offset..])? {
+            records.push(record);
+            offset += frame_len;
+        }
+
+        // Drop whatever follows the last complete record
+        let len = offset as u64;
+        if offset < bytes.len() {
+            file.set_len(len)?;
+            file.sync_all()?;
+        }
+        file.seek(SeekFrom::Start(len))?;
+
+        Ok((BlockStore { file, len }, records))
+    }
+
+    /// Append a record to the log, returning only once it is flushed to disk
+    pub fn append(&mut self, record: &Record) -> io::Result<()> {
+        let frame = encode(record);
+        let result = self.file.write_all(&frame).and_then(|_| self.file.sync_data());
+
+        if let Err(e) = result {
+            // Roll back a partially written frame so later appends stay readable
+            self.file.set_len(self.len)?;
+            self.file.seek(SeekFrom::Start(self.len))?;
+            return Err(e);
+        }
+
+        self.len += frame.len() as u64;
+        Ok(())
+    }
+}
+
+// Header of a log in the current format
+fn log_header() -> [u8; LOG_HEADER_SIZE] {
+    let mut header = [0u8; LOG_HEADER_SIZE];
+    header[..MAGIC.len()].copy_from_slice(&MAGIC);
+    header[MAGIC.len()..].copy_from_slice(&FORMAT_VERSION.to_le_bytes());
+    header
+}
+
+// Check that the log starts with the header of the current format
+fn check_log_header(bytes: &[u8]) -> io::Result<()> {
+    if bytes.len() < LOG_HEADER_SIZE || bytes[..MAGIC.len()] != MAGIC {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                  "block log has no format header; it was written by an older version"));
+    }
+    let mut version = [0u8; VERSION_SIZE];
+    version.copy_from_slice(&bytes[MAGIC.len()..LOG_HEADER_SIZE]);
+    let version = u32::from_le_bytes(version);
+    if version != FORMAT_VERSION {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                  format!("block log has format version {}, expected {}", version, FORMAT_VERSION)));
+    }
+    Ok(())
+}
+
+// Checksum of a record's payload: the first bytes of its SHA256 digest
+fn checksum(payload: &[u8]) -> [u8; CHECKSUM_SIZE] {
+    let digest = ring::digest::digest(&ring::digest::SHA256, payload);
+    let mut checksum = [0u8; CHECKSUM_SIZE];
+    checksum.copy_from_slice(&digest.as_ref()[..CHECKSUM_SIZE]);
+    checksum
+}
+
+// Serialize a record into a framed byte buffer
+fn encode(record: &Record) -> Vec<u8> {
+    let payload = bincode::serialize(record).unwrap();
+    let mut frame = Vec::with_capacity(FRAME_HEADER_SIZE + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    frame.extend_from_slice(&checksum(&payload));
+    frame.extend_from_slice(&payload);
+    frame
+}
+
+// Parse the framed record at the start of `bytes`, along with the size of its frame, or
+// None if the record is incomplete or corrupt. A record that passes its checksum but does
+// not decode was written in another layout, which is an error rather than a torn write.
+fn decode(bytes: &[u8]) -> io::Result<Option<(Record, usize)>> {
+    if bytes.len() < FRAME_HEADER_SIZE {
+        return Ok(None);
+    }
+
+    let mut length = [0u8; LENGTH_SIZE];
+    length.copy_from_slice(&bytes[..LENGTH_SIZE]);
+    let frame_len = FRAME_HEADER_SIZE + u32::from_le_bytes(length) as usize;
+    if bytes.len() < frame_len {
+        return Ok(None);    // record was only partially written
+    }
+
+    let payload = &bytes[FRAME_HEADER_SIZE..frame_len];
+    if bytes[LENGTH_SIZE..FRAME_HEADER_SIZE] != checksum(payload) {
+        return Ok(None);    // record is corrupt
+    }
+
+    match bincode::deserialize(payload) {
+        Ok(record) => Ok(Some((record, frame_len))),
+        Err(e) => Err(io::Error::new(io::ErrorKind::InvalidData, format!("undecodable block log record: {}", e))),
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::blockchain::{test_params, Blockchain};
+    use crate::types::block::generate_random_block;
+    use crate::types::hash::{generate_random_hash, Hashable};
+    use rand::Rng;
+    use std::path::PathBuf;
+
+    /// A fresh directory under the system temp dir, removed when dropped
+    pub struct TempDir(pub PathBuf);
+
+    impl TempDir {
+        pub fn new() -> Self {
+            Self::default()
+        }
+    }
+
+    impl Default for TempDir {
+        fn default() -> Self {
+            let name = format!("bitcoin-store-{:016x}", rand::thread_rng().gen::<u64>());
+            TempDir(std::env::temp_dir().join(name))
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    // Blocks extending the genesis block one after another
+    fn random_chain(count: usize) -> Vec<Block> {
+        let mut blockchain = Blockchain::with_params(test_params());
+        (0..count).map(|_| {
+            let block = generate_random_block(&blockchain, &blockchain.tip());
+            blockchain.insert(&block).unwrap();
+            block
+        }).collect()
+    }
+
+    fn log_len(dir: &TempDir) -> u64 {
+        fs::metadata(dir.0.join(LOG_FILE)).unwrap().len()
+    }
+
+    // Simulate a process killed halfway through appending `record`
+    fn write_torn(dir: &TempDir, record: &Record) {
+        let frame = encode(record);
+        let mut file = OpenOptions::new().append(true).open(dir.0.join(LOG_FILE)).unwrap();
+        file.write_all(&frame[..frame.len() / 2]).unwrap();
+    }
+
+    #[test]
+    fn reopen_returns_records_in_order() {
+        let dir = TempDir::new();
+        let block = random_chain(1).remove(0);
+        let tip = block.hash();
+
+        let (mut store, records) = BlockStore::open(&dir.0).unwrap();
+        assert!(records.is_empty());
+        store.append(&Record::Block { block: Box::new(block.clone()), height: 1 }).unwrap();
+        store.append(&Record::Tip(tip)).unwrap();
+        drop(store);
+
+        let (_, records) = BlockStore::open(&dir.0).unwrap();
+        assert_eq!(records.len(), 2);
+        match &records[0] {
+            Record::Block { block: b, height } => {
+                assert_eq!(b.hash(), tip);
+                assert_eq!(*height, 1);
+            }
+            _ => panic!(),
+        }
+        match &records[1] {
+            Record::Tip(h) => assert_eq!(*h, tip),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn torn_record_is_truncated() {
+        let dir = TempDir::new();
+        let (block1, block2) = match &random_chain(2)[..] {
+            [block1, block2] => (block1.clone(), block2.clone()),
+            _ => unreachable!(),
+        };
+
+        let (mut store, _) = BlockStore::open(&dir.0).unwrap();
+        store.append(&Record::Block { block: Box::new(block1.clone()), height: 1 }).unwrap();
+        drop(store);
+        let complete_len = log_len(&dir);
+
+        // Killed while writing block2
+        write_torn(&dir, &Record::Block { block: Box::new(block2.clone()), height: 2 });
+        assert!(log_len(&dir) > complete_len);
+
+        let (mut store, records) = BlockStore::open(&dir.0).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(log_len(&dir), complete_len);
+
+        // Appending after recovery produces a readable log
+        store.append(&Record::Block { block: Box::new(block2.clone()), height: 2 }).unwrap();
+        drop(store);
+        let (_, records) = BlockStore::open(&dir.0).unwrap();
+        assert_eq!(records.len(), 2);
+    }
+
+    #[test]
+    fn corrupt_record_is_truncated() {
+        let dir = TempDir::new();
+        let block = random_chain(1).remove(0);
+
+        let (mut store, _) = BlockStore::open(&dir.0).unwrap();
+        store.append(&Record::Tip(block.hash())).unwrap();
+        store.append(&Record::Block { block: Box::new(block), height: 1 }).unwrap();
+        drop(store);
+
+        // Flip the last byte of the log
+        let path = dir.0.join(LOG_FILE);
+        let mut bytes = fs::read(&path).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+        fs::write(&path, &bytes).unwrap();
+
+        let (_, records) = BlockStore::open(&dir.0).unwrap();
+        assert_eq!(records.len(), 1);
+    }
+
+    #[test]
+    fn undecodable_record_is_an_error() {
+        let dir = TempDir::new();
+        let block = random_chain(1).remove(0);
+
+        let (mut store, _) = BlockStore::open(&dir.0).unwrap();
+        store.append(&Record::Tip(block.hash())).unwrap();
+        drop(store);
+
+        // A record with a valid checksum that no longer decodes, as after a layout change
+        let payload = vec![0xff; 16];
+        let mut frame = (payload.len() as u32).to_le_bytes().to_vec();
+        frame.extend_from_slice(&checksum(&payload));
+        frame.extend_from_slice(&payload);
+        let mut file = OpenOptions::new().append(true).open(dir.0.join(LOG_FILE)).unwrap();
+        file.write_all(&frame).unwrap();
+        drop(file);
+        let len = log_len(&dir);
+
+        let error = BlockStore::open(&dir.0).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(log_len(&dir), len);
+    }
+
+    #[test]
+    fn other_format_is_an_error() {
+        let dir = TempDir::new();
+        let (store, _) = BlockStore::open(&dir.0).unwrap();
+        drop(store);
+        assert_eq!(log_len(&dir), LOG_HEADER_SIZE as u64);
+
+        // A log from a later version
+        let path = dir.0.join(LOG_FILE);
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[MAGIC.len()..].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+        fs::write(&path, &bytes).unwrap();
+        assert_eq!(BlockStore::open(&dir.0).err().unwrap().kind(), io::ErrorKind::InvalidData);
+
+        // A log from before the header was written, starting straight with a record
+        let frame = encode(&Record::Tip(generate_random_hash()));
+        fs::write(&path, &frame).unwrap();
+        assert_eq!(BlockStore::open(&dir.0).err().unwrap().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(log_len(&dir), frame.len() as u64);
+    }
+}
@@ -0,0 +1,64 @@
+use crate::types::{
+    address::Address,
+    block::Block,
+    hash::{H256, Hashable},
+    transaction::SignedTransaction,
+};
+use std::collections::HashMap;
+
+// A TxIndex locates the transactions of the longest chain by hash and by address
+#[derive(Debug, Default)]
+pub struct TxIndex {
+    by_hash: HashMap<H256, (H256, usize)>,      // <txn hash, (block hash, position in block)>
+    by_address: HashMap<Address, Vec<H256>>,    // <address, txns sent or received, oldest first>
+}
+
+impl TxIndex {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Index the transactions of a block joining the longest chain
+    pub fn connect(&mut self, block: &Block) {
+        let block_hash = block.hash();
+        for (position, txn) in block.content.transactions.iter().enumerate() {
+            let txn_hash = txn.hash();
+            self.by_hash.insert(txn_hash, (block_hash, position));
+            for address in Self::addresses(txn) {
+                self.by_address.entry(address).or_default().push(txn_hash);
+            }
+        }
+    }
+
+    /// Remove the transactions of a block leaving the longest chain. Blocks must be
+    /// disconnected from the tip down, so their transactions are last in every history.
+    pub fn disconnect(&mut self, block: &Block) {
+        for txn in block.content.transactions.iter().rev() {
+            self.by_hash.remove(&txn.hash());
+            for address in Self::addresses(txn) {
+                let history = self.by_address.get_mut(&address).unwrap();
+                history.pop();
+                if history.is_empty() {
+                    self.by_address.remove(&address);
+                }
+            }
+        }
+    }
+
+    /// Block and position of a transaction in the longest chain
+    pub fn get(&self, txn_hash: &H256) -> Option<(H256, usize)> {
+        self.by_hash.get(txn_hash).copied()
+    }
+
+    /// Hashes of the transactions sent or received by `address`, oldest first
+    pub fn address_transactions(&self, address: &Address) -> &[H256] {
+        self.by_address.get(address).map_or(&[], |history| &history[..])
+    }
+
+    // The sender, then the receiver unless the transaction pays its own sender
+    fn addresses(txn: &SignedTransaction) -> Vec<Address> {
+        let sender = Address::from_public_key_bytes(&txn.public_key);
+        let receiver = txn.transaction.receiver;
+        if sender == receiver { vec![sender] } else { vec![sender, receiver] }
+    }
+}
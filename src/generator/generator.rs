@@ -1,11 +1,11 @@
-use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
-use log::info;
+use crossbeam::channel::Receiver;
+use log::{debug, info};
 use std::{
-    time,
     sync::{Arc, Mutex},
     thread,
 };
 use crate::{
+    miner::Handle as MinerHandle,
     network::server::Handle as ServerHandle,
     network::message::Message,
     types::{
@@ -20,19 +20,22 @@ use crate::{
 pub struct TransactionGenerator {
     server: ServerHandle,
     finished_txn_chan: Receiver<SignedTransaction>,
-    mempool: Arc<Mutex<Mempool>>
+    mempool: Arc<Mutex<Mempool>>,
+    miner: MinerHandle    // rebuilds its block template when a better transaction arrives
 }
 
 impl TransactionGenerator {
     pub fn new(
         server: &ServerHandle,
         finished_txn_chan: Receiver<SignedTransaction>,
-        mempool: &Arc<Mutex<Mempool>>
+        mempool: &Arc<Mutex<Mempool>>,
+        miner: &MinerHandle
     ) -> Self {
         Self {
             server: server.clone(),
-            finished_txn_chan: finished_txn_chan,
-            mempool: Arc::clone(mempool)
+            finished_txn_chan,
+            mempool: Arc::clone(mempool),
+            miner: miner.clone()
         }
     }
 
@@ -54,9 +57,20 @@ impl TransactionGenerator {
             
             // Insert this transaction into mempool
             let mut mempool = self.mempool.lock().unwrap();
-            mempool.map.insert(txn.hash(), txn.clone());    // insert txn into mempool
-            println!("Inserted transaction into mempool");
+            let result = mempool.insert(txn.clone());
             drop(mempool);
+            match result {
+                Ok(inserted) => {
+                    if inserted.improves_template {
+                        self.miner.update();
+                    }
+                }
+                Err(e) => {
+                    debug!("Generated transaction {} not added to mempool: {}", txn.hash(), e);
+                    continue;
+                }
+            }
+            println!("Inserted transaction into mempool");
             
             // Broadcast transaction hash as a NewTransactionHashes message
             let hash = vec![txn.hash()];
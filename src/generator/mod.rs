@@ -1,11 +1,12 @@
+#[allow(clippy::module_inception)]
 pub mod generator;
 
-use log::info;
+use log::{info, warn};
+use serde::Serialize;
 use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
 use rand::Rng;
 use std::{
     sync::{Arc, Mutex},
-    time::{SystemTime, UNIX_EPOCH},
     time,
     thread,
 };
@@ -15,22 +16,44 @@ use crate::types::{
     transaction::{SignedTransaction, Transaction},
     mempool::Mempool,
     address::Address,
-    key_pair,
 };
 use ring::signature::{Ed25519KeyPair, KeyPair};
 
+// Largest amount above the minimum relay fee paid by a generated transaction
+const MAX_EXTRA_FEE: u128 = 10;
+
 enum ControlSignal {
     Start(u64), // the number controls the theta of interval between transaction generation
     Update, // update the transaction in generation (not sure if necessary)
+    Pause,
+    Resume, // continue with the last theta
     Exit,
 }
 
+#[derive(Clone, Copy)]
 enum OperatingState {
     Paused,
     Run(u64),
     ShutDown,
 }
 
+// Progress of the generator thread, shared with its handles
+struct Shared {
+    operating_state: OperatingState,
+    theta: Option<u64>,     // last theta the generator was started with
+    transactions_generated: u64,
+    created: time::Instant,
+}
+
+/// What the generator is doing, as reported by `Handle::status`
+#[derive(Serialize, Debug, Clone)]
+pub struct Status {
+    pub state: &'static str,    // "paused", "running" or "shutdown"
+    pub theta: Option<u64>,
+    pub transactions_generated: u64,
+    pub uptime_secs: u64,
+}
+
 pub struct Context {
     /// Channel for receiving control signal
     control_chan: Receiver<ControlSignal>,
@@ -38,28 +61,38 @@ pub struct Context {
     finished_txn_chan: Sender<SignedTransaction>,
     mempool: Arc<Mutex<Mempool>>,
     blockchain: Arc<Mutex<Blockchain>>,
+    shared: Arc<Mutex<Shared>>,
 }
 
 #[derive(Clone)]
 pub struct Handle {
     /// Channel for sending signal to the generator thread
     control_chan: Sender<ControlSignal>,
+    shared: Arc<Mutex<Shared>>,
 }
 
 pub fn new(blockchain: &Arc<Mutex<Blockchain>>, mempool: &Arc<Mutex<Mempool>>) -> (Context, Handle, Receiver<SignedTransaction>) {
     let (signal_chan_sender, signal_chan_receiver) = unbounded();
     let (finished_txn_sender, finished_txn_receiver) = unbounded();
+    let shared = Arc::new(Mutex::new(Shared {
+        operating_state: OperatingState::Paused,
+        theta: None,
+        transactions_generated: 0,
+        created: time::Instant::now(),
+    }));
 
     let ctx = Context {
         control_chan: signal_chan_receiver,
         operating_state: OperatingState::Paused,
         finished_txn_chan: finished_txn_sender,
         mempool: Arc::clone(mempool),
-        blockchain: Arc::clone(blockchain)
+        blockchain: Arc::clone(blockchain),
+        shared: Arc::clone(&shared),
     };
 
     let handle = Handle {
         control_chan: signal_chan_sender,
+        shared,
     };
 
     (ctx, handle, finished_txn_receiver)
@@ -67,17 +100,44 @@ pub fn new(blockchain: &Arc<Mutex<Blockchain>>, mempool: &Arc<Mutex<Mempool>>) -
 
 impl Handle {
     pub fn exit(&self) {
-        self.control_chan.send(ControlSignal::Exit).unwrap();
+        self.send(ControlSignal::Exit);
     }
 
-    pub fn start(&self, lambda: u64) {
-        self.control_chan
-            .send(ControlSignal::Start(lambda))
-            .unwrap();
+    pub fn start(&self, theta: u64) {
+        self.send(ControlSignal::Start(theta));
     }
 
     pub fn update(&self) {
-        self.control_chan.send(ControlSignal::Update).unwrap();
+        self.send(ControlSignal::Update);
+    }
+
+    pub fn pause(&self) {
+        self.send(ControlSignal::Pause);
+    }
+
+    pub fn resume(&self) {
+        self.send(ControlSignal::Resume);
+    }
+
+    pub fn status(&self) -> Status {
+        let shared = self.shared.lock().unwrap();
+        Status {
+            state: match shared.operating_state {
+                OperatingState::Paused => "paused",
+                OperatingState::Run(_) => "running",
+                OperatingState::ShutDown => "shutdown",
+            },
+            theta: shared.theta,
+            transactions_generated: shared.transactions_generated,
+            uptime_secs: shared.created.elapsed().as_secs(),
+        }
+    }
+
+    // Signals sent after the generator has shut down are dropped
+    fn send(&self, signal: ControlSignal) {
+        if self.control_chan.send(signal).is_err() {
+            warn!("Transaction Generator has shut down, ignoring control signal");
+        }
     }
 }
 
@@ -92,6 +152,16 @@ impl Context {
         info!("Transaction Generator initialized into paused mode");
     }
 
+    // Switch operating state, recording it and the theta for the handles
+    fn set_state(&mut self, operating_state: OperatingState) {
+        self.operating_state = operating_state;
+        let mut shared = self.shared.lock().unwrap();
+        shared.operating_state = operating_state;
+        if let OperatingState::Run(theta) = operating_state {
+            shared.theta = Some(theta);
+        }
+    }
+
     fn generator_loop(&mut self) {
         // main transaction generator loop
         loop {
@@ -102,13 +172,23 @@ impl Context {
                     match signal {
                         ControlSignal::Exit => {
                             info!("Transaction Generator shutting down");
-                            self.operating_state = OperatingState::ShutDown;
+                            self.set_state(OperatingState::ShutDown);
                         }
                         ControlSignal::Start(i) => {
                             info!("Transaction Generator starting in continuous mode with theta {}", i);
-                            self.operating_state = OperatingState::Run(i);
+                            self.set_state(OperatingState::Run(i));
+                        }
+                        ControlSignal::Resume => {
+                            let theta = self.shared.lock().unwrap().theta;
+                            match theta {
+                                Some(i) => {
+                                    info!("Transaction Generator resuming with theta {}", i);
+                                    self.set_state(OperatingState::Run(i));
+                                }
+                                None => warn!("Transaction Generator was never started, ignoring resume"),
+                            }
                         }
-                        ControlSignal::Update => {
+                        ControlSignal::Update | ControlSignal::Pause => {
                             // in paused state, don't need to update
                         }
                     };
@@ -122,14 +202,18 @@ impl Context {
                         match signal {
                             ControlSignal::Exit => {
                                 info!("Transaction Generator shutting down");
-                                self.operating_state = OperatingState::ShutDown;
+                                self.set_state(OperatingState::ShutDown);
                             }
                             ControlSignal::Start(i) => {
                                 info!("Transaction Generator starting in continuous mode with theta {}", i);
-                                self.operating_state = OperatingState::Run(i);
+                                self.set_state(OperatingState::Run(i));
                             }
-                            ControlSignal::Update => {
-                                unimplemented!()
+                            ControlSignal::Pause => {
+                                info!("Transaction Generator pausing");
+                                self.set_state(OperatingState::Paused);
+                            }
+                            ControlSignal::Resume | ControlSignal::Update => {
+                                // every transaction is built from the current tip already
                             }
                         };
                     }
@@ -162,9 +246,20 @@ impl Context {
             let sender_address = Address::from_public_key_bytes(&sender_public_key);
 
             // Get the chosen sender's info from parent state
-            let sender_info = parent_state.map[&sender_address];
-            let sender_nonce = sender_info.0;
-            let sender_balance = sender_info.1;
+            let sender_info = parent_state.get(&sender_address).unwrap();
+            let mut sender_nonce = sender_info.0;
+            let mut sender_balance = sender_info.1;
+
+            // Chain onto the sender's transactions still pending in the mempool
+            let mempool = self.mempool.lock().unwrap();
+            let min_fee = mempool.config().min_relay_fee;
+            for txn in mempool.sender_transactions(&sender_address) {
+                if txn.transaction.account_nonce == sender_nonce + 1 {
+                    sender_nonce += 1;
+                    sender_balance = sender_balance.saturating_sub(txn.transaction.value.saturating_add(txn.transaction.fee));
+                }
+            }
+            drop(mempool);
 
             // Skip if the chosen sender has no balance
             if sender_balance == 0 {
@@ -188,11 +283,19 @@ impl Context {
             }
             let value = rng.gen_range(1..max_value);
 
+            // Choose a random fee the sender can still afford, at least the minimum relay fee
+            let max_fee = (min_fee + MAX_EXTRA_FEE).min(sender_balance - value);
+            if max_fee < min_fee {
+                continue;
+            }
+            let fee = rng.gen_range(min_fee..=max_fee);
+
             // Form the transaction
             let transaction = Transaction {
                 account_nonce: sender_nonce + 1,    // increment previous nonce
                 receiver: receiver_address, 
-                value: value
+                value,
+                fee
             };
 
             // Sign the transaction
@@ -200,8 +303,8 @@ impl Context {
 
             // Form the signed transaction
             let signed_transaction = SignedTransaction {
-                transaction: transaction, 
-                signature: signature, 
+                transaction, 
+                signature, 
                 public_key: sender_public_key
             };
 
@@ -209,10 +312,11 @@ impl Context {
    
             // Send signed transaction to channel
             self.finished_txn_chan.send(signed_transaction.clone()).expect("Sending to finished_txn_chan resulted in error.");
+            self.shared.lock().unwrap().transactions_generated += 1;
             
             if let OperatingState::Run(i) = self.operating_state {
                 if i != 0 {
-                    let interval = time::Duration::from_micros(i * 200 as u64);
+                    let interval = time::Duration::from_micros(i * 200_u64);
                     thread::sleep(interval);
                 }
             }
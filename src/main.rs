@@ -9,18 +9,34 @@ pub mod miner;
 pub mod network;
 pub mod generator;
 
-use blockchain::Blockchain;
-use types::mempool::Mempool;
+use blockchain::{Blockchain, ChainParams};
+use types::{address::Address, mempool::{Mempool, MempoolConfig}};
 use clap::clap_app;
 use smol::channel;
-use log::{error, info};
+use log::{error, info, warn};
 use api::Server as ApiServer;
 use std::net;
 use std::process;
+use std::str::FromStr;
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time;
 
+// Parse the value of a command line option, or return `default` if it was not given
+fn parse_arg<T>(matches: &clap::ArgMatches, name: &str, default: T) -> T
+where
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    match matches.value_of(name) {
+        Some(value) => value.parse::<T>().unwrap_or_else(|e| {
+            error!("Error parsing {}: {}", name, e);
+            process::exit(1);
+        }),
+        None => default,
+    }
+}
+
 fn main() {
     // parse command line arguments
     let matches = clap_app!(Bitcoin =>
@@ -31,6 +47,16 @@ fn main() {
      (@arg api_addr: --api [ADDR] default_value("127.0.0.1:7000") "Sets the IP address and the port of the API server")
      (@arg known_peer: -c --connect ... [PEER] "Sets the peers to connect to at start")
      (@arg p2p_workers: --("p2p-workers") [INT] default_value("4") "Sets the number of worker threads for P2P server")
+     (@arg data_dir: --("data-dir") [DIR] "Sets the directory where the blockchain is persisted")
+     (@arg txindex: --txindex "Indexes the transactions of the longest chain by hash and by address")
+     (@arg block_subsidy: --("block-subsidy") [INT] "Sets the subsidy minted by the coinbase of the first blocks")
+     (@arg halving_interval: --("halving-interval") [BLOCKS] "Sets the number of blocks after which the block subsidy halves, or 0 to never halve it")
+     (@arg miner_address: --("miner-address") [ADDR] "Sets the address that receives the rewards of mined blocks")
+     (@arg miner_threads: --("miner-threads") [INT] "Sets the number of threads searching for proof-of-work")
+     (@arg mempool_max_txs: --("mempool-max-txs") [INT] "Sets the largest number of transactions kept in the mempool")
+     (@arg mempool_max_bytes: --("mempool-max-bytes") [INT] "Sets the largest total size of the mempool in bytes")
+     (@arg min_relay_fee: --("min-relay-fee") [INT] "Sets the smallest fee of a transaction accepted into the mempool")
+     (@arg mempool_expiry: --("mempool-expiry") [SECS] "Sets how long a transaction may stay in the mempool")
     )
     .get_matches();
 
@@ -38,9 +64,41 @@ fn main() {
     let verbosity = matches.occurrences_of("verbose") as usize;
     stderrlog::new().verbosity(verbosity).init().unwrap();
     
-    let blockchain = Blockchain::new();
+    // configure the consensus parameters, keeping those of the main chain for options not given
+    let defaults = ChainParams::default();
+    let params = ChainParams {
+        initial_block_subsidy: parse_arg(&matches, "block_subsidy", defaults.initial_block_subsidy),
+        subsidy_halving_interval: parse_arg(&matches, "halving_interval", defaults.subsidy_halving_interval),
+        ..defaults
+    };
+
+    // open the persistent blockchain if a data directory is given
+    let mut blockchain = match matches.value_of("data_dir") {
+        Some(dir) => Blockchain::open(dir, params).unwrap_or_else(|e| {
+            error!("Error opening blockchain in {}: {}", dir, e);
+            process::exit(1);
+        }),
+        None => Blockchain::with_params(params),
+    };
+    if matches.is_present("txindex") {
+        blockchain.enable_txindex();
+    }
     let blockchain = Arc::new(Mutex::new(blockchain));
-    let mempool = Mempool::new();
+
+    // configure the mempool limits, keeping the defaults for options not given
+    let defaults = MempoolConfig::default();
+    let mempool_config = MempoolConfig {
+        max_transactions: parse_arg(&matches, "mempool_max_txs", defaults.max_transactions),
+        max_bytes: parse_arg(&matches, "mempool_max_bytes", defaults.max_bytes),
+        min_relay_fee: parse_arg(&matches, "min_relay_fee", defaults.min_relay_fee),
+        expiry: time::Duration::from_secs(parse_arg(&matches, "mempool_expiry", defaults.expiry.as_secs())),
+        ..defaults
+    };
+    let mut mempool = Mempool::with_config(mempool_config);
+    {
+        let blockchain = blockchain.lock().unwrap();
+        mempool.revalidate(blockchain.get_state(&blockchain.tip()).unwrap());
+    }
     let mempool = Arc::new(Mutex::new(mempool));
 
     // parse p2p server address
@@ -73,7 +131,7 @@ fn main() {
     // create blockchain
     // NOT SURE HOW TO DO THIS
 
-    // start the worker
+    // parse the number of p2p workers
     let p2p_workers = matches
         .value_of("p2p_workers")
         .unwrap()
@@ -82,24 +140,40 @@ fn main() {
             error!("Error parsing P2P workers: {}", e);
             process::exit(1);
         });
+
+    // parse the address paid by mined blocks
+    let miner_address = match matches.value_of("miner_address") {
+        Some(addr) => addr.parse::<Address>().unwrap_or_else(|e| {
+            error!("Error parsing miner address: {}", e);
+            process::exit(1);
+        }),
+        None => {
+            warn!("No miner address given, block rewards will be sent to the zero address");
+            Address::default()
+        }
+    };
+
+    // start the miner
+    let miner_threads = parse_arg(&matches, "miner_threads", 1);
+    let (miner_ctx, miner, finished_block_chan) = miner::new(&blockchain, &mempool, miner_address, miner_threads);
+    let miner_worker_ctx = miner::worker::Worker::new(&server, finished_block_chan);
+    miner_ctx.start();
+    miner_worker_ctx.start();
+
+    // start the worker, which has the miner follow new tips
     let worker_ctx = network::worker::Worker::new(
         p2p_workers,
         msg_rx,
         &server,
         &blockchain,
         &mempool,
+        &miner,
     );
     worker_ctx.start();
-
-    // start the miner
-    let (miner_ctx, miner, finished_block_chan) = miner::new(&blockchain, &mempool);
-    let miner_worker_ctx = miner::worker::Worker::new(&server, finished_block_chan, &blockchain);
-    miner_ctx.start();
-    miner_worker_ctx.start();
     
     // start the transaction generator
     let (generator_ctx, txn_generator, finished_txn_chan) = generator::new(&blockchain, &mempool);
-    let generator_worker_ctx = generator::generator::TransactionGenerator::new(&server, finished_txn_chan, &mempool);
+    let generator_worker_ctx = generator::generator::TransactionGenerator::new(&server, finished_txn_chan, &mempool, &miner);
     generator_ctx.start();
     generator_worker_ctx.start();
     
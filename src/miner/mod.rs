@@ -1,36 +1,78 @@
+pub mod stats;
 pub mod worker;
 
-use log::info;
-use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
-use rand::Rng;
+use log::{error, info, warn};
+use crossbeam::channel::{bounded, unbounded, Receiver, Select, Sender, TryRecvError};
 use std::{
-    sync::{Arc, Mutex},
-    time::{SystemTime, UNIX_EPOCH},
+    ops::RangeInclusive,
+    sync::{atomic::{self, AtomicBool, AtomicU64}, Arc, Mutex},
+    time::{Instant, SystemTime, UNIX_EPOCH},
     time,
     thread,
 };
-use crate::blockchain::Blockchain;
+use crate::blockchain::{self, BlockError, Blockchain, ChainUpdate};
+use self::stats::{MinerStats, Stats};
 use crate::types::{
     address::Address,
-    block::{Block, Content, Header},
+    block::{Block, Content, Header, HeaderBuffer},
     hash::{Hashable, H256},
-    transaction::SignedTransaction,
-    merkle::MerkleTree,
-    mempool::Mempool
+    transaction::{CoinbaseTransaction, SignedTransaction},
+    mempool::{compare_fee_rate, Mempool},
+    state::State,
 };
+use serde::Serialize;
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap, VecDeque};
 
+// Hashes a mining thread tries between checks of the stop flag and the clock
+const HASH_BATCH: u64 = 4096;
+
+// How often hashes are counted into the statistics while searching
+const STATS_INTERVAL: time::Duration = time::Duration::from_secs(1);
+
+// Outcome of searching the nonces of a header
+enum Search {
+    Found(u32, u128),    // nonce and timestamp giving a hash that meets the difficulty
+    Exhausted,           // every nonce was tried
+    Interrupted,         // a control signal arrived
+}
+
+#[derive(Debug)]
 enum ControlSignal {
     Start(u64), // the number controls the lambda of interval between block generation
     Update, // update the block in mining, it may due to new blockchain tip or new transaction
+    Pause,
+    Resume, // continue with the last lambda
     Exit,
 }
 
+#[derive(Debug, Clone, Copy)]
 enum OperatingState {
     Paused,
     Run(u64),
     ShutDown,
 }
 
+// Progress of the miner thread, shared with its handles
+#[derive(Debug)]
+struct Shared {
+    operating_state: OperatingState,
+    lambda: Option<u64>,    // last lambda the miner was started with
+    created: Instant,
+    stats: Stats,
+}
+
+/// What the miner is doing, as reported by `Handle::status`
+#[derive(Serialize, Debug, Clone)]
+pub struct Status {
+    pub state: &'static str,    // "paused", "running" or "shutdown"
+    pub lambda: Option<u64>,
+    pub threads: usize,
+    pub blocks_mined: u64,
+    pub hashrate: u64,
+    pub uptime_secs: u64,
+}
+
 pub struct Context {
     /// Channel for receiving control signal
     control_chan: Receiver<ControlSignal>,
@@ -38,56 +80,117 @@ pub struct Context {
     finished_block_chan: Sender<Block>,
     blockchain: Arc<Mutex<Blockchain>>,
     mempool: Arc<Mutex<Mempool>>,
+    miner_address: Address,    // receives the block rewards
+    threads: usize,            // mining threads, each searching its own share of the nonces
+    shared: Arc<Mutex<Shared>>,
 }
 
-#[derive(Clone)]
+#[derive(Debug, Clone)]
 pub struct Handle {
     /// Channel for sending signal to the miner thread
     control_chan: Sender<ControlSignal>,
+    threads: usize,
+    shared: Arc<Mutex<Shared>>,
 }
 
-// set upper limit on number of transactions per block
-const BLOCK_SIZE_LIMIT: usize = 30;      
-
-pub fn new(blockchain: &Arc<Mutex<Blockchain>>, mempool: &Arc<Mutex<Mempool>>) -> (Context, Handle, Receiver<Block>) {
+pub fn new(
+    blockchain: &Arc<Mutex<Blockchain>>,
+    mempool: &Arc<Mutex<Mempool>>,
+    miner_address: Address,
+    threads: usize
+) -> (Context, Handle, Receiver<Block>) {
+    let threads = threads.max(1);
+    let now = Instant::now();
     let (signal_chan_sender, signal_chan_receiver) = unbounded();
     let (finished_block_sender, finished_block_receiver) = unbounded();
+    let shared = Arc::new(Mutex::new(Shared {
+        operating_state: OperatingState::Paused,
+        lambda: None,
+        created: now,
+        stats: Stats::new(now),
+    }));
 
     let ctx = Context {
         control_chan: signal_chan_receiver,
         operating_state: OperatingState::Paused,
         finished_block_chan: finished_block_sender,
         blockchain: Arc::clone(blockchain),
-        mempool: Arc::clone(mempool)
+        mempool: Arc::clone(mempool),
+        miner_address,
+        threads,
+        shared: Arc::clone(&shared),
     };
 
     let handle = Handle {
         control_chan: signal_chan_sender,
+        threads,
+        shared,
     };
 
     (ctx, handle, finished_block_receiver)
 }
 
-#[cfg(any(test,test_utilities))]
+#[cfg(any(test, feature = "test-utilities"))]
 fn test_new() -> (Context, Handle, Receiver<Block>) {
-    let blockchain = Arc::new(Mutex::new(Blockchain::new()));
+    let blockchain = Arc::new(Mutex::new(Blockchain::with_params(blockchain::test_params())));
     let mempool = Arc::new(Mutex::new(Mempool::new()));
-    new(&blockchain, &mempool)
+    new(&blockchain, &mempool, Address::default(), 2)
 }
 
 impl Handle {
     pub fn exit(&self) {
-        self.control_chan.send(ControlSignal::Exit).unwrap();
+        self.send(ControlSignal::Exit);
     }
 
     pub fn start(&self, lambda: u64) {
-        self.control_chan
-            .send(ControlSignal::Start(lambda))
-            .unwrap();
+        self.send(ControlSignal::Start(lambda));
     }
 
     pub fn update(&self) {
-        self.control_chan.send(ControlSignal::Update).unwrap();
+        self.send(ControlSignal::Update);
+    }
+
+    pub fn pause(&self) {
+        self.send(ControlSignal::Pause);
+    }
+
+    pub fn resume(&self) {
+        self.send(ControlSignal::Resume);
+    }
+
+    pub fn status(&self) -> Status {
+        let shared = self.shared.lock().unwrap();
+        Status {
+            state: match shared.operating_state {
+                OperatingState::Paused => "paused",
+                OperatingState::Run(_) => "running",
+                OperatingState::ShutDown => "shutdown",
+            },
+            lambda: shared.lambda,
+            threads: self.threads,
+            blocks_mined: shared.stats.blocks_mined(),
+            hashrate: shared.stats.template_hashrate(),
+            uptime_secs: shared.created.elapsed().as_secs(),
+        }
+    }
+
+    /// Hashrates, blocks found and time per block, with the mined blocks that left the
+    /// longest chain counted as stale
+    pub fn stats(&self) -> MinerStats {
+        self.shared.lock().unwrap().stats.report(Instant::now())
+    }
+
+    /// Count the recently mined blocks a change of the longest chain makes stale, or no
+    /// longer stale. Every block inserted into the blockchain must be reported.
+    pub fn chain_updated(&self, update: &ChainUpdate) {
+        self.shared.lock().unwrap().stats.record_chain_update(update);
+    }
+
+    // Signals sent after the miner has shut down are dropped
+    fn send(&self, signal: ControlSignal) {
+        if self.control_chan.send(signal).is_err() {
+            warn!("Miner has shut down, ignoring control signal");
+        }
     }
 }
 
@@ -102,9 +205,19 @@ impl Context {
         info!("Miner initialized into paused mode");
     }
 
+    // Switch operating state, recording it and the lambda for the handles
+    fn set_state(&mut self, operating_state: OperatingState) {
+        self.operating_state = operating_state;
+        let mut shared = self.shared.lock().unwrap();
+        shared.operating_state = operating_state;
+        if let OperatingState::Run(lambda) = operating_state {
+            shared.lambda = Some(lambda);
+        }
+    }
+
     fn miner_loop(&mut self) {
         // main mining loop
-        loop {
+        'mining: loop {
             // check and react to control signals
             match self.operating_state {
                 OperatingState::Paused => {
@@ -112,13 +225,23 @@ impl Context {
                     match signal {
                         ControlSignal::Exit => {
                             info!("Miner shutting down");
-                            self.operating_state = OperatingState::ShutDown;
+                            self.set_state(OperatingState::ShutDown);
                         }
                         ControlSignal::Start(i) => {
                             info!("Miner starting in continuous mode with lambda {}", i);
-                            self.operating_state = OperatingState::Run(i);
+                            self.set_state(OperatingState::Run(i));
                         }
-                        ControlSignal::Update => {
+                        ControlSignal::Resume => {
+                            let lambda = self.shared.lock().unwrap().lambda;
+                            match lambda {
+                                Some(i) => {
+                                    info!("Miner resuming with lambda {}", i);
+                                    self.set_state(OperatingState::Run(i));
+                                }
+                                None => warn!("Miner was never started, ignoring resume"),
+                            }
+                        }
+                        ControlSignal::Update | ControlSignal::Pause => {
                             // in paused state, don't need to update
                         }
                     };
@@ -127,147 +250,359 @@ impl Context {
                 OperatingState::ShutDown => {
                     return;
                 }
-                _ => match self.control_chan.try_recv() {
-                    Ok(signal) => {
-                        match signal {
-                            ControlSignal::Exit => {
-                                info!("Miner shutting down");
-                                self.operating_state = OperatingState::ShutDown;
-                            }
-                            ControlSignal::Start(i) => {
-                                info!("Miner starting in continuous mode with lambda {}", i);
-                                self.operating_state = OperatingState::Run(i);
-                            }
-                            ControlSignal::Update => {
-                                unimplemented!()
-                            }
-                        };
+                // take every pending signal, leaving the rest to the paused state if one pauses
+                _ => while let OperatingState::Run(_) = self.operating_state {
+                    match self.control_chan.try_recv() {
+                        Ok(signal) => {
+                            match signal {
+                                ControlSignal::Exit => {
+                                    info!("Miner shutting down");
+                                    self.set_state(OperatingState::ShutDown);
+                                }
+                                ControlSignal::Start(i) => {
+                                    info!("Miner starting in continuous mode with lambda {}", i);
+                                    self.set_state(OperatingState::Run(i));
+                                }
+                                ControlSignal::Pause => {
+                                    info!("Miner pausing");
+                                    self.set_state(OperatingState::Paused);
+                                }
+                                ControlSignal::Resume => {
+                                    // already running
+                                }
+                                ControlSignal::Update => {
+                                    // the block template is rebuilt below
+                                }
+                            };
+                        }
+                        Err(TryRecvError::Empty) => break,
+                        Err(TryRecvError::Disconnected) => panic!("Miner control channel detached"),
                     }
-                    Err(TryRecvError::Empty) => {}
-                    Err(TryRecvError::Disconnected) => panic!("Miner control channel detached"),
                 },
             }
-            if let OperatingState::ShutDown = self.operating_state {
-                return;
+            match self.operating_state {
+                OperatingState::ShutDown => return,
+                OperatingState::Paused => continue,
+                OperatingState::Run(_) => {}
             }
 
             // TODO for student: actual mining, create a block
             // TODO for student: if block mining finished, you can have something like: 
             // self.finished_block_chan.send(block.clone()).expect("Send finished block error");
 
-            // println!("Starting the Mining Process...");
-            
-            // Get current tip of blockchain to get parent_block, parent_state, difficulty 
+            // Build a block on the current tip; its transactions stay in the mempool until the
+            // block joins the main chain
             let blockchain = self.blockchain.lock().unwrap();
-            let parent_hash = blockchain.tip();
-            let parent_block = match blockchain.get_block(&parent_hash) {
-                Ok(block) => block,    // parent exists in blockchain
-                Err(_) => panic!("Parent node does not exist in blockchain."),   // parent not found
+            let mut mempool = self.mempool.lock().unwrap();
+            mempool.expire(Instant::now());
+            let template = block_template(&blockchain, &mempool, self.miner_address);
+            if let Ok(block) = &template {
+                let floor = template_floor(&mempool, &block.content.transactions);
+                mempool.set_template_floor(floor);
+            }
+            drop(mempool);
+            drop(blockchain);
+            let Block { header: template, mut content } = match template {
+                Ok(block) => block,
+                Err(e) => {
+                    error!("Block template is invalid: {}", e);
+                    continue;
+                }
             };
-            let parent_state = match blockchain.get_state(&parent_hash) {
-                Ok(state) => state.clone(),    // parent exists in blockchain
-                Err(_) => panic!("Parent node does not exist in blockchain."),   // parent not found
+            let (parent_hash, difficulty) = (template.parent, template.difficulty);
+
+            // Scan the nonces for the desired hash, bumping the extra nonce for a fresh merkle root
+            // whenever they run out, unless a control signal such as a new tip or a better paying
+            // transaction asks for a new template first
+            let header = loop {
+                let header = Header { merkle_root: content.merkle_root(), ..template.clone() };
+                match self.search(&HeaderBuffer::new(&header), &difficulty, header.timestamp) {
+                    Search::Found(nonce, timestamp) => break Header { nonce, timestamp, ..header },
+                    Search::Exhausted => {
+                        content.coinbase.extra_nonce += 1;
+                        info!("Nonces exhausted on {}, moving to extra nonce {}", parent_hash, content.coinbase.extra_nonce);
+                    }
+                    Search::Interrupted => continue 'mining,
+                }
             };
-            let difficulty = parent_block.get_difficulty();
-            let mut rng = rand::thread_rng();
-            drop(blockchain);
+            let block = Block { header, content };
 
-            // Prepare to get transactions for the new block
-            let mut mempool = self.mempool.lock().unwrap();
-            let mut transactions: Vec<SignedTransaction> = Vec::new();
-            let mut removal_hashes = Vec::new();
-
-            // Iterate over the transactions in the mempool
-            for txn in mempool.map.values() {
-                // Break if the block transaction limit is reached
-                if transactions.len() == BLOCK_SIZE_LIMIT {
-                    break;
-                }
+            info!("Mined block {} on {}", block.hash(), parent_hash);
 
-                let sender_address = Address::from_public_key_bytes(&txn.public_key);
-                let sender_info = parent_state.map[&sender_address];
+            // Insert block into blockchain, so the next block is mined on top of it
+            let mut blockchain = self.blockchain.lock().unwrap();
+            match blockchain.insert(&block) {
+                Ok(update) => {
+                    self.mempool.lock().unwrap().update_chain(&blockchain, &update);
+                    let mut shared = self.shared.lock().unwrap();
+                    shared.stats.record_chain_update(&update);
+                    shared.stats.record_block(block.hash());
+                    drop(shared);
+                    drop(blockchain);
 
-                // Check nonce, balance, and if sender is already included in the new block
-                let is_nonce_valid = txn.transaction.account_nonce == sender_info.0 + 1;
-                let is_balance_sufficient = txn.transaction.value <= sender_info.1;
-                let is_sender_unique = !transactions.iter().any(|x| Address::from_public_key_bytes(&x.public_key) == sender_address);
-                
-                if is_nonce_valid && is_balance_sufficient && is_sender_unique {
-                    transactions.push(txn.clone());
-                }
-                else {
-                    println!("Miner found invalid transaction");
+                    // Send to channel
+                    self.finished_block_chan.send(block).expect("Sending to channel resulted in error.");
                 }
+                Err(e) => error!("Mined block {} was rejected: {}", block.hash(), e),
+            }
 
-                // Schedule the processed transaction for removal from the mempool
-                removal_hashes.push(txn.hash());
+            if let OperatingState::Run(i) = self.operating_state {
+                if i != 0 {
+                    let interval = time::Duration::from_micros(i);
+                    thread::sleep(interval);
+                }
             }
+        }
+    }
 
-            // Remove the processed transactions from the mempool
-            for txn_hash in removal_hashes {
-                mempool.map.remove(&txn_hash);
+    // Search the nonce space on every mining thread until one finds a hash meeting `difficulty`,
+    // every nonce was tried, or a control signal arrives. The signal is left on the channel for
+    // the main loop.
+    fn search(&self, buffer: &HeaderBuffer, difficulty: &H256, min_timestamp: u128) -> Search {
+        let stop = AtomicBool::new(false);
+        let hashes = AtomicU64::new(0);
+        let started = Instant::now();
+        let (found_sender, found_receiver) = bounded(self.threads);
+
+        // Move the hashes counted by the threads into the statistics
+        let mut template_hashes = 0;
+        let mut record_hashes = || {
+            let counted = hashes.swap(0, atomic::Ordering::Relaxed);
+            template_hashes += counted;
+            self.shared.lock().unwrap().stats.record_hashes(Instant::now(), counted);
+        };
+
+        let interrupted = thread::scope(|scope| {
+            for nonces in nonce_ranges(self.threads) {
+                let (buffer, found_sender) = (buffer.clone(), found_sender.clone());
+                let (stop, hashes) = (&stop, &hashes);
+                thread::Builder::new()
+                    .name("miner-worker".to_string())
+                    .spawn_scoped(scope, move || {
+                        if let Some(solution) = search_nonces(buffer, nonces, difficulty, min_timestamp, stop, hashes) {
+                            let _ = found_sender.send(solution);
+                        }
+                    })
+                    .unwrap();
             }
-            
-            // Stop mining current block if there are no transactions
-            if transactions.len() == 0 {
-                continue;
+            drop(found_sender);
+
+            let mut select = Select::new();
+            let found = select.recv(&found_receiver);
+            select.recv(&self.control_chan);
+            let ready = loop {
+                match select.ready_timeout(STATS_INTERVAL) {
+                    Ok(index) => break index,
+                    Err(_) => record_hashes(),
+                }
+            };
+            stop.store(true, atomic::Ordering::Relaxed);
+            ready != found
+        });
+
+        // Every thread has stopped, so a nonce found while a control signal arrived is kept
+        let solution = match found_receiver.try_recv() {
+            Ok((nonce, timestamp)) => Search::Found(nonce, timestamp),
+            Err(_) if interrupted => Search::Interrupted,
+            Err(_) => Search::Exhausted,    // every thread finished its share
+        };
+        record_hashes();
+        self.shared.lock().unwrap().stats.record_search(started.elapsed(), template_hashes);
+        solution
+    }
+}
+
+// Split the nonce space into one contiguous share per mining thread
+fn nonce_ranges(threads: usize) -> Vec<RangeInclusive<u32>> {
+    let space = u32::MAX as u64 + 1;
+    let threads = threads as u64;
+    (0..threads)
+        .map(|i| (space * i / threads) as u32..=(space * (i + 1) / threads - 1) as u32)
+        .collect()
+}
+
+// Try the nonces of `nonces` in order until one gives a hash meeting `difficulty`, returning
+// it with the timestamp it was hashed with, or until they run out or `stop` is set. The
+// timestamp rolls forward with the clock every batch, but never below `min_timestamp` so the
+// block stays valid when the clock lags the median time past. Attempts are added to `hashes`.
+fn search_nonces(
+    mut buffer: HeaderBuffer,
+    nonces: RangeInclusive<u32>,
+    difficulty: &H256,
+    min_timestamp: u128,
+    stop: &AtomicBool,
+    hashes: &AtomicU64
+) -> Option<(u32, u128)> {
+    let (mut next, last) = (*nonces.start() as u64, *nonces.end() as u64);
+    while next <= last && !stop.load(atomic::Ordering::Relaxed) {
+        let timestamp = match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(time) => time.as_millis().max(min_timestamp),
+            Err(_) => panic!("SystemTime before UNIX EPOCH!"), 
+        };
+        buffer.set_timestamp(timestamp);
+
+        let batch_last = last.min(next + HASH_BATCH - 1);
+        for nonce in next..=batch_last {
+            buffer.set_nonce(nonce as u32);
+            if buffer.hash() <= *difficulty {
+                hashes.fetch_add(nonce - next + 1, atomic::Ordering::Relaxed);
+                stop.store(true, atomic::Ordering::Relaxed);
+                return Some((nonce as u32, timestamp));
             }
+        }
+        hashes.fetch_add(batch_last - next + 1, atomic::Ordering::Relaxed);
+        next = batch_last + 1;
+    }
+    None
+}
 
-            drop(mempool);
+/// Build a block on the tip of `blockchain` paying `receiver` the subsidy and the fees of the
+/// best paying transactions of `mempool`, committing to them and to the state they leave.
+/// The nonce is left for the miner to fill in, and the timestamp is the earliest the block
+/// may carry, which the miner moves up to the current time.
+pub fn block_template(blockchain: &Blockchain, mempool: &Mempool, receiver: Address) -> Result<Block, BlockError> {
+    let parent_hash = blockchain.tip();
+    let parent_state = blockchain.get_state(&parent_hash).expect("Tip does not exist in blockchain.");
+    let difficulty = blockchain.next_difficulty(&parent_hash).expect("Tip does not exist in blockchain.");
+    let height = blockchain.get_height(&parent_hash).expect("Tip does not exist in blockchain.") + 1;
+    let min_timestamp = blockchain.min_timestamp(&parent_hash).expect("Tip does not exist in blockchain.");
+
+    // Pay the block subsidy; fees are added once transactions are chosen
+    let mut coinbase = CoinbaseTransaction {
+        height,
+        receiver,
+        value: blockchain.params().block_subsidy(height),
+        extra_nonce: 0
+    };
 
-            // Get other attributes for current block
-            let merkle_tree = MerkleTree::new(&transactions.clone());
-            let merkle_root = merkle_tree.root();
-            
-            // Loop to generate random nonces until desired hash is achieved
-            while self.blockchain.lock().unwrap().tip() == parent_hash  {
-                let content = Content{ 
-                    transactions: transactions.clone() 
-                };
-
-                let nonce: u32 = rng.gen::<u32>();      // generate a random nonce
-                
-                let timestamp: u128 = match SystemTime::now().duration_since(UNIX_EPOCH) {
-                    Ok(time) => time.as_millis(),
-                    Err(_) => panic!("SystemTime before UNIX EPOCH!"), 
-                };
-                
-                let header = Header {
-                    parent: parent_hash,
-                    nonce: nonce,
-                    difficulty: difficulty,
-                    timestamp: timestamp,
-                    merkle_root: merkle_root
-                };
-
-                let block = Block{ header, content };
-                
-                if block.hash() <= difficulty {
-                    // Desired nonce found!
-                    println!("Desired nonce found!");
-                    println!("Parent Hash: {}", parent_hash);
-                    println!("Block Hash : {}", block.hash());
-
-                    // Insert block into blockchain (temporary)
-                    // match {self.blockchain.lock().unwrap().insert(&block)} {
-                    //     Ok(_) => println!("SUCCESS - inserted block into blockchain"),
-                    //     Err(e) => panic!("{}", e)
-                    // };
+    // Fill the space left by the header and coinbase with the best paying transactions
+    let size_limit = blockchain::MAX_BLOCK_SIZE - empty_block_size(&coinbase);
+    let transactions = select_transactions(mempool, parent_state, size_limit);
+    coinbase.value += transactions.iter().map(|txn| txn.transaction.fee).sum::<u128>();
+
+    let content = Content { coinbase, transactions };
+    let header = Header {
+        parent: parent_hash,
+        nonce: 0,
+        difficulty,
+        timestamp: min_timestamp,
+        merkle_root: content.merkle_root(),
+        state_root: blockchain.state_root_after(&parent_hash, &content)?
+    };
+    Ok(Block { header, content })
+}
 
-                    // Send to channel
-                    self.finished_block_chan.send(block.clone()).expect("Sending to channel resulted in error.");
-                    break;
-                }
-            }
-            
-            if let OperatingState::Run(i) = self.operating_state {
-                if i != 0 {
-                    let interval = time::Duration::from_micros(i as u64);
-                    thread::sleep(interval);
-                }
+// Fee and size of the lowest fee rate transaction in a block template, or None if the
+// template holds every ready transaction of the mempool
+fn template_floor(mempool: &Mempool, transactions: &[SignedTransaction]) -> Option<(u128, usize)> {
+    if transactions.len() >= mempool.stats().ready {
+        return None;
+    }
+    transactions.iter()
+        .map(|txn| (txn.transaction.fee, bincode::serialized_size(txn).unwrap() as usize))
+        .min_by(|a, b| compare_fee_rate(a.0, a.1, b.0, b.1))
+}
+
+// Serialized size of a block holding only `coinbase`
+fn empty_block_size(coinbase: &CoinbaseTransaction) -> usize {
+    let block = Block {
+        header: Header {
+            parent: H256::default(),
+            nonce: 0,
+            difficulty: H256::default(),
+            timestamp: 0,
+            merkle_root: H256::default(),
+            state_root: H256::default()
+        },
+        content: Content { coinbase: coinbase.clone(), transactions: Vec::new() }
+    };
+    bincode::serialized_size(&block).unwrap() as usize
+}
+
+// A transaction that may be added to the block being assembled
+struct Candidate {
+    txn: SignedTransaction,
+    size: usize,
+}
+
+impl Candidate {
+    fn new(txn: &SignedTransaction) -> Self {
+        let size = bincode::serialized_size(txn).unwrap() as usize;
+        Candidate { txn: txn.clone(), size }
+    }
+}
+
+// Candidates are ordered by fee per byte, breaking ties by total fee
+impl Ord for Candidate {
+    fn cmp(&self, other: &Candidate) -> Ordering {
+        compare_fee_rate(self.txn.transaction.fee, self.size, other.txn.transaction.fee, other.size)
+            .then(self.txn.transaction.fee.cmp(&other.txn.transaction.fee))
+    }
+}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Candidate) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Candidate {
+    fn eq(&self, other: &Candidate) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Candidate {}
+
+/// Choose the mempool transactions for a block on top of `parent_state`, filling at most
+/// `size_limit` bytes so as to maximise the total fee. A sender's transactions must be
+/// included in nonce order, so the best paying of the senders' next transactions is
+/// taken greedily by fee per byte.
+fn select_transactions(mempool: &Mempool, parent_state: &State, size_limit: usize) -> Vec<SignedTransaction> {
+    // Each sender's run of consecutive nonces following its account nonce
+    let mut chains: HashMap<Address, VecDeque<&SignedTransaction>> = HashMap::new();
+    for sender in mempool.senders() {
+        let account_nonce = parent_state.get(sender).map_or(0, |info| info.0);
+        let mut chain = VecDeque::new();
+        for txn in mempool.sender_transactions(sender) {
+            let nonce = txn.transaction.account_nonce;
+            if nonce <= account_nonce {
+                continue;    // already used; the mempool drops it on revalidation
+            } else if nonce == account_nonce + 1 + chain.len() as u128 {
+                chain.push_back(txn);
+            } else {
+                break;    // the rest wait for a missing nonce
             }
         }
+        chains.insert(*sender, chain);
+    }
+
+    let mut heads: BinaryHeap<Candidate> = chains.values_mut()
+        .filter_map(|chain| chain.pop_front())
+        .map(Candidate::new)
+        .collect();
+
+    let mut state = parent_state.clone();
+    let mut transactions = Vec::new();
+    let mut remaining = size_limit;
+    while let Some(candidate) = heads.pop() {
+        // A sender's later nonces cannot be included without this transaction
+        if candidate.size > remaining {
+            continue;
+        }
+        if blockchain::validate_transaction(&state, &candidate.txn).is_err() {
+            continue;    // sender cannot afford it yet
+        }
+        blockchain::apply_transaction(&mut state, &candidate.txn);
+        remaining -= candidate.size;
+
+        let sender = Address::from_public_key_bytes(&candidate.txn.public_key);
+        if let Some(next) = chains.get_mut(&sender).and_then(|chain| chain.pop_front()) {
+            heads.push(Candidate::new(next));
+        }
+        transactions.push(candidate.txn);
     }
+    transactions
 }
 
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
@@ -275,7 +610,268 @@ impl Context {
 #[cfg(test)]
 mod test {
     use ntest::timeout;
-    use crate::types::hash::Hashable;
+    use crate::types::hash::{Hashable, H256};
+    use crate::types::{
+        address::Address,
+        block::{Block, Content, Header, HeaderBuffer},
+        mempool::Mempool,
+        state::State,
+        transaction::{CoinbaseTransaction, SignedTransaction},
+    };
+    use crate::blockchain::tests::signed_transaction;
+    use crate::types::address::generate_random_address;
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+    use std::sync::atomic::{self, AtomicBool, AtomicU64};
+    use std::thread;
+    use std::time::{self, Instant, SystemTime, UNIX_EPOCH};
+
+    // State where the accounts with seeds 0..4 hold 1000 each
+    fn funded_state() -> State {
+        let mut state = State::new();
+        for seed in 0..4 {
+            let key = Ed25519KeyPair::from_seed_unchecked(&[seed; 32]).unwrap();
+            state.insert(Address::from_public_key_bytes(key.public_key().as_ref()), (0, 1000));
+        }
+        state
+    }
+
+    fn mempool_with(txns: &[&SignedTransaction]) -> Mempool {
+        let mut mempool = Mempool::new();
+        for txn in txns {
+            assert_eq!(mempool.insert((*txn).clone()).unwrap().replaced, None);
+        }
+        mempool
+    }
+
+    fn hashes(txns: &[SignedTransaction]) -> Vec<H256> {
+        txns.iter().map(|txn| txn.hash()).collect()
+    }
+
+    #[test]
+    fn select_transactions_by_fee_rate() {
+        let low = signed_transaction(0, 1, generate_random_address(), 10, 5);
+        let high = signed_transaction(1, 1, generate_random_address(), 10, 30);
+        let medium = signed_transaction(2, 1, generate_random_address(), 10, 10);
+        let mempool = mempool_with(&[&low, &high, &medium]);
+
+        // Room for exactly two transactions
+        let size = bincode::serialized_size(&low).unwrap() as usize;
+        let transactions = super::select_transactions(&mempool, &funded_state(), 2 * size + 1);
+        assert_eq!(hashes(&transactions), vec![high.hash(), medium.hash()]);
+
+        // Everything fits in a larger block
+        let transactions = super::select_transactions(&mempool, &funded_state(), 10 * size);
+        assert_eq!(hashes(&transactions), vec![high.hash(), medium.hash(), low.hash()]);
+    }
+
+    #[test]
+    fn select_transactions_chains_nonces() {
+        let mut state = funded_state();
+        let first = signed_transaction(0, 1, generate_random_address(), 10, 1);
+        let second = signed_transaction(0, 2, generate_random_address(), 10, 40);
+        let third = signed_transaction(0, 3, generate_random_address(), 10, 2);
+        let other = signed_transaction(1, 1, generate_random_address(), 10, 20);
+        let after_gap = signed_transaction(1, 3, generate_random_address(), 10, 50);
+        let overspend = signed_transaction(2, 1, generate_random_address(), 1000, 1);
+        let stale = signed_transaction(3, 1, generate_random_address(), 10, 1);
+        let mempool = mempool_with(&[&first, &second, &third, &other, &after_gap, &overspend, &stale]);
+        state.insert(Address::from_public_key_bytes(&stale.public_key), (1, 1000));
+
+        // The low fee first transaction unlocks the high fee second one
+        let transactions = super::select_transactions(&mempool, &state, 100_000);
+        assert_eq!(hashes(&transactions), vec![other.hash(), first.hash(), second.hash(), third.hash()]);
+    }
+
+    #[test]
+    fn header_buffer_hashes_like_header() {
+        let mut header = Header {
+            parent: [1u8; 32].into(),
+            nonce: 7,
+            difficulty: [2u8; 32].into(),
+            timestamp: 1_600_000_000_000,
+            merkle_root: [3u8; 32].into(),
+            state_root: [4u8; 32].into(),
+        };
+        let mut buffer = HeaderBuffer::new(&header);
+        assert_eq!(buffer.hash(), header.hash());
+
+        header.nonce = u32::MAX - 5;
+        header.timestamp = u128::MAX / 3;
+        buffer.set_nonce(header.nonce);
+        buffer.set_timestamp(header.timestamp);
+        assert_eq!(buffer.hash(), header.hash());
+    }
+
+    #[test]
+    fn nonce_ranges_partition_the_nonce_space() {
+        for threads in 1..=7 {
+            let ranges = super::nonce_ranges(threads);
+            assert_eq!(ranges.len(), threads);
+            assert_eq!(*ranges[0].start(), 0);
+            assert_eq!(*ranges[threads - 1].end(), u32::MAX);
+            for pair in ranges.windows(2) {
+                assert_eq!(*pair[0].end() as u64 + 1, *pair[1].start() as u64);
+            }
+        }
+    }
+
+    #[test]
+    fn search_nonces_scans_in_order() {
+        let header = Header {
+            parent: [5u8; 32].into(),
+            nonce: 0,
+            difficulty: H256::default(),
+            timestamp: 0,
+            merkle_root: [6u8; 32].into(),
+            state_root: [7u8; 32].into(),
+        };
+        let mut buffer = HeaderBuffer::new(&header);
+        let difficulty: H256 = [0x10; 32].into();    // met by about one hash in sixteen
+        let (stop, hashes) = (AtomicBool::new(false), AtomicU64::new(0));
+
+        // The first nonce of the range meeting the difficulty at the timestamp used is found
+        let (nonce, timestamp) = super::search_nonces(buffer.clone(), 100..=1099, &difficulty, 0, &stop, &hashes).unwrap();
+        buffer.set_timestamp(timestamp);
+        let first = (100..=1099).find(|nonce| {
+            buffer.set_nonce(*nonce);
+            buffer.hash() <= difficulty
+        });
+        assert_eq!(Some(nonce), first);
+        assert_eq!(hashes.into_inner(), (nonce - 100 + 1) as u64);
+        assert!(stop.into_inner());
+
+        // A clock behind the median time past does not take the timestamp below it
+        let min_timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() + 3_600_000;
+        let (stop, hashes) = (AtomicBool::new(false), AtomicU64::new(0));
+        let (_, timestamp) = super::search_nonces(buffer, 100..=1099, &difficulty, min_timestamp, &stop, &hashes).unwrap();
+        assert_eq!(timestamp, min_timestamp);
+    }
+
+    #[test]
+    fn search_nonces_exhausts_range() {
+        let buffer = HeaderBuffer::new(&Header {
+            parent: H256::default(),
+            nonce: 0,
+            difficulty: H256::default(),
+            timestamp: 0,
+            merkle_root: H256::default(),
+            state_root: H256::default(),
+        });
+        let (stop, hashes) = (AtomicBool::new(false), AtomicU64::new(0));
+
+        // Every nonce up to the end of the space is tried once, without wrapping around
+        let unreachable = H256::default();
+        assert_eq!(super::search_nonces(buffer.clone(), u32::MAX - 9..=u32::MAX, &unreachable, 0, &stop, &hashes), None);
+        assert_eq!(hashes.into_inner(), 10);
+
+        // A stopped search tries nothing
+        let (stop, hashes) = (AtomicBool::new(true), AtomicU64::new(0));
+        assert_eq!(super::search_nonces(buffer, 0..=u32::MAX, &unreachable, 0, &stop, &hashes), None);
+        assert_eq!(hashes.into_inner(), 0);
+    }
+
+    #[test]
+    fn extra_nonce_changes_merkle_root() {
+        let mut content = Content {
+            coinbase: CoinbaseTransaction { height: 1, receiver: Address::default(), value: 101, extra_nonce: 0 },
+            transactions: vec![signed_transaction(0, 1, generate_random_address(), 10, 1)],    // paying a fee of 1
+        };
+        let merkle_root = content.merkle_root();
+        let state = crate::blockchain::next_state(&Default::default(), &std::sync::Arc::new(funded_state()), &content, 1).unwrap();
+
+        content.coinbase.extra_nonce += 1;
+        assert_ne!(content.merkle_root(), merkle_root);
+        let bumped = crate::blockchain::next_state(&Default::default(), &std::sync::Arc::new(funded_state()), &content, 1).unwrap();
+        assert_eq!(bumped.accounts(), state.accounts());
+    }
+
+    // Compares the hashrate of re-serializing the whole block every attempt, as the miner
+    // used to, with the header buffer on one and on every available thread. Run with
+    // `cargo test --release hashrate_benchmark -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn hashrate_benchmark() {
+        const DURATION: time::Duration = time::Duration::from_secs(2);
+        let content = Content {
+            coinbase: CoinbaseTransaction { height: 1, receiver: Address::default(), value: 100, extra_nonce: 0 },
+            transactions: (0..100).map(|i| signed_transaction(0, i + 1, generate_random_address(), 10, 1)).collect(),
+        };
+        let header = Header {
+            parent: H256::default(),
+            nonce: 0,
+            difficulty: H256::default(),    // unreachable, so every search runs until stopped
+            timestamp: 0,
+            merkle_root: content.merkle_root(),
+            state_root: H256::default(),
+        };
+
+        let started = Instant::now();
+        let mut hashes = 0u64;
+        while started.elapsed() < DURATION {
+            let mut block = Block { header: header.clone(), content: content.clone() };
+            block.header.nonce = hashes as u32;
+            block.header.timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
+            assert!(block.hash() > header.difficulty);
+            hashes += 1;
+        }
+        println!("re-serialized block: {:>12} H/s", hashes / DURATION.as_secs());
+
+        let available = thread::available_parallelism().map_or(1, |n| n.get());
+        let thread_counts = if available > 1 { vec![1, available] } else { vec![1] };
+        for threads in thread_counts {
+            let stop = AtomicBool::new(false);
+            let hashes = AtomicU64::new(0);
+            let buffer = HeaderBuffer::new(&header);
+            thread::scope(|scope| {
+                for nonces in super::nonce_ranges(threads) {
+                    let (buffer, stop, hashes) = (buffer.clone(), &stop, &hashes);
+                    let difficulty = &header.difficulty;
+                    scope.spawn(move || super::search_nonces(buffer, nonces, difficulty, 0, stop, hashes));
+                }
+                thread::sleep(DURATION);
+                stop.store(true, atomic::Ordering::Relaxed);
+            });
+            println!("header buffer, {:>2} threads: {:>12} H/s", threads, hashes.into_inner() / DURATION.as_secs());
+        }
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn miner_pause_resume_exit() {
+        let (miner_ctx, miner_handle, finished_block_chan) = super::test_new();
+        miner_ctx.start();
+        assert_eq!(miner_handle.status().state, "paused");
+
+        // Resuming a miner that was never started does nothing
+        miner_handle.resume();
+        miner_handle.start(0);
+        finished_block_chan.recv().unwrap();
+        let status = miner_handle.status();
+        assert_eq!((status.state, status.lambda, status.threads), ("running", Some(0), 2));
+        assert!(status.blocks_mined >= 1);
+
+        // The block in progress may still be finished, but no more after that
+        miner_handle.pause();
+        while miner_handle.status().state != "paused" {
+            thread::sleep(time::Duration::from_millis(10));
+        }
+        let mined = miner_handle.status().blocks_mined;
+        thread::sleep(time::Duration::from_millis(100));
+        assert_eq!(miner_handle.status().blocks_mined, mined);
+        while finished_block_chan.try_recv().is_ok() {}
+
+        miner_handle.resume();
+        finished_block_chan.recv().unwrap();
+        assert!(miner_handle.status().blocks_mined > mined);
+
+        // Signals sent after exiting are dropped
+        miner_handle.exit();
+        while miner_handle.status().state != "shutdown" {
+            thread::sleep(time::Duration::from_millis(10));
+        }
+        miner_handle.pause();
+        assert_eq!(miner_handle.status().state, "shutdown");
+    }
 
     #[test]
     #[timeout(60000)]
@@ -0,0 +1,247 @@
+use crate::blockchain::ChainUpdate;
+use crate::types::hash::H256;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::time::{Duration, Instant};
+
+/// Lengths of the sliding windows hashrates are averaged over, in seconds
+pub const HASHRATE_WINDOWS: [u64; 3] = [10, 60, 600];
+
+/// Number of the latest found blocks whose place in the longest chain is followed
+pub const RECENT_BLOCKS: usize = 100;
+
+// Counters kept by the miner thread, from which MinerStats are reported
+#[derive(Debug)]
+pub struct Stats {
+    started: Instant,
+    hashes: u64,
+    samples: VecDeque<(Instant, u64)>,    // hashes counted at each instant, within the longest window
+    template_hashrate: u64,               // hashes per second over the last template
+    recent: VecDeque<(H256, bool)>,       // latest accepted blocks, oldest first, and whether each is in the longest chain
+    blocks_found: u64,
+    stale_blocks: u64,                    // of the recent blocks, or as they were when they left the window
+    searching: Duration,                  // spent searching since the last block was found
+    last_block_time: Option<Duration>,
+    total_block_time: Duration,
+}
+
+impl Stats {
+    pub fn new(now: Instant) -> Self {
+        Stats {
+            started: now,
+            hashes: 0,
+            samples: VecDeque::new(),
+            template_hashrate: 0,
+            recent: VecDeque::new(),
+            blocks_found: 0,
+            stale_blocks: 0,
+            searching: Duration::ZERO,
+            last_block_time: None,
+            total_block_time: Duration::ZERO,
+        }
+    }
+
+    /// Count hashes attempted up to `now`
+    pub fn record_hashes(&mut self, now: Instant, hashes: u64) {
+        self.hashes += hashes;
+        self.samples.push_back((now, hashes));
+        let longest = Duration::from_secs(HASHRATE_WINDOWS[HASHRATE_WINDOWS.len() - 1]);
+        while let Some((instant, _)) = self.samples.front() {
+            if now.duration_since(*instant) <= longest {
+                break;
+            }
+            self.samples.pop_front();
+        }
+    }
+
+    /// Account for the time spent searching one template and the hashes it took
+    pub fn record_search(&mut self, elapsed: Duration, hashes: u64) {
+        self.searching += elapsed;
+        if elapsed > Duration::ZERO {
+            self.template_hashrate = (hashes as f64 / elapsed.as_secs_f64()) as u64;
+        }
+    }
+
+    /// Count a mined block accepted into the blockchain
+    pub fn record_block(&mut self, hash: H256) {
+        self.blocks_found += 1;
+        self.recent.push_back((hash, true));
+        if self.recent.len() > RECENT_BLOCKS {
+            self.recent.pop_front();
+        }
+        self.last_block_time = Some(self.searching);
+        self.total_block_time += self.searching;
+        self.searching = Duration::ZERO;
+    }
+
+    /// Follow the recent blocks out of and back into the longest chain
+    pub fn record_chain_update(&mut self, update: &ChainUpdate) {
+        for (hash, in_chain) in self.recent.iter_mut() {
+            if *in_chain && update.disconnected.contains(hash) {
+                *in_chain = false;
+                self.stale_blocks += 1;
+            } else if !*in_chain && update.connected.contains(hash) {
+                *in_chain = true;
+                self.stale_blocks -= 1;
+            }
+        }
+    }
+
+    pub fn blocks_mined(&self) -> u64 {
+        self.blocks_found
+    }
+
+    pub fn template_hashrate(&self) -> u64 {
+        self.template_hashrate
+    }
+
+    // Hashes per second over the last `window` seconds, or since the miner was created if sooner
+    fn hashrate(&self, now: Instant, window: u64) -> f64 {
+        let window = Duration::from_secs(window);
+        let hashes: u64 = self.samples.iter()
+            .filter(|(instant, _)| now.duration_since(*instant) <= window)
+            .map(|(_, hashes)| hashes)
+            .sum();
+        let span = window.min(now.duration_since(self.started)).as_secs_f64();
+        if span > 0.0 { hashes as f64 / span } else { 0.0 }
+    }
+
+    /// Report the counters
+    pub fn report(&self, now: Instant) -> MinerStats {
+        let blocks_found = self.blocks_found;
+        MinerStats {
+            hashes: self.hashes,
+            hashrates: HASHRATE_WINDOWS.iter()
+                .map(|window| Hashrate { window_secs: *window, hashes_per_sec: self.hashrate(now, *window) })
+                .collect(),
+            blocks_found,
+            stale_blocks: self.stale_blocks,
+            last_block_secs: self.last_block_time.map(|time| time.as_secs_f64()),
+            mean_block_secs: if blocks_found > 0 {
+                Some(self.total_block_time.as_secs_f64() / blocks_found as f64)
+            } else {
+                None
+            },
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Hashrate {
+    pub window_secs: u64,
+    pub hashes_per_sec: f64,
+}
+
+/// Mining statistics served by the API
+#[derive(Serialize, Debug, Clone)]
+pub struct MinerStats {
+    pub hashes: u64,                     // attempted since the miner was created
+    pub hashrates: Vec<Hashrate>,        // one per sliding window
+    pub blocks_found: u64,               // mined and accepted into the blockchain
+    pub stale_blocks: u64,               // found blocks that left the longest chain
+    pub last_block_secs: Option<f64>,    // time spent searching for the last block found
+    pub mean_block_secs: Option<f64>,
+}
+
+impl MinerStats {
+    /// Render the statistics in the Prometheus text exposition format
+    pub fn metrics(&self) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, samples: &[(String, f64)]| {
+            writeln!(out, "# HELP {} {}", name, help).unwrap();
+            writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+            for (labels, value) in samples {
+                writeln!(out, "{}{} {}", name, labels, value).unwrap();
+            }
+        };
+        let unlabelled = |value: f64| vec![(String::new(), value)];
+
+        metric("miner_hashes_total", "counter", "Hashes attempted by the miner.", &unlabelled(self.hashes as f64));
+        let hashrates: Vec<(String, f64)> = self.hashrates.iter()
+            .map(|rate| (format!("{{window=\"{}s\"}}", rate.window_secs), rate.hashes_per_sec))
+            .collect();
+        metric("miner_hashrate", "gauge", "Hashes per second over a sliding window.", &hashrates);
+        metric("miner_blocks_found_total", "counter", "Blocks mined and accepted into the blockchain.", &unlabelled(self.blocks_found as f64));
+        metric("miner_stale_blocks", "gauge", "Mined blocks no longer in the longest chain.", &unlabelled(self.stale_blocks as f64));
+        if let Some(secs) = self.last_block_secs {
+            metric("miner_last_block_seconds", "gauge", "Time spent searching for the last block found.", &unlabelled(secs));
+        }
+        if let Some(secs) = self.mean_block_secs {
+            metric("miner_mean_block_seconds", "gauge", "Mean time spent searching per block found.", &unlabelled(secs));
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Stats, RECENT_BLOCKS};
+    use crate::blockchain::{test_params, Blockchain};
+    use crate::blockchain::tests::{child_block, seal_block};
+    use crate::types::block::generate_timestamp;
+    use crate::types::hash::Hashable;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn hashrate_windows_and_stale_blocks() {
+        let start = Instant::now();
+        let mut stats = Stats::new(start);
+        stats.record_hashes(start + Duration::from_secs(5), 1000);
+        stats.record_hashes(start + Duration::from_secs(100), 6000);
+
+        // Only the last sample is within the 10 and 60 second windows
+        let now = start + Duration::from_secs(105);
+        let report = stats.report(now);
+        assert_eq!(report.hashes, 7000);
+        let rates: Vec<f64> = report.hashrates.iter().map(|rate| rate.hashes_per_sec).collect();
+        assert_eq!(rates, vec![600.0, 100.0, 7000.0 / 105.0]);
+
+        // A found block turns stale once a longer fork replaces it
+        let mut blockchain = Blockchain::with_params(test_params());
+        let genesis = blockchain.tip();
+        let mut mined = child_block(&blockchain, &genesis, generate_timestamp());
+        seal_block(&blockchain, &mut mined);
+        stats.record_chain_update(&blockchain.insert(&mined).unwrap());
+        stats.record_search(Duration::from_secs(4), 400);
+        stats.record_block(mined.hash());
+        assert_eq!(stats.template_hashrate(), 100);
+        let report = stats.report(now);
+        assert_eq!((report.blocks_found, report.stale_blocks), (1, 0));
+        assert_eq!((report.last_block_secs, report.mean_block_secs), (Some(4.0), Some(4.0)));
+
+        let mut parent = genesis;
+        for _ in 0..2 {
+            let mut block = child_block(&blockchain, &parent, generate_timestamp());
+            block.content.coinbase.extra_nonce = 1;    // differ from the mined block
+            seal_block(&blockchain, &mut block);
+            stats.record_chain_update(&blockchain.insert(&block).unwrap());
+            parent = block.hash();
+        }
+        assert_eq!(stats.report(now).stale_blocks, 1);
+
+        let metrics = stats.report(now).metrics();
+        assert!(metrics.contains("miner_hashrate{window=\"60s\"} 100\n"));
+        assert!(metrics.contains("miner_stale_blocks 1\n"));
+
+        // Back in the longest chain it is no longer stale
+        let mut child = child_block(&blockchain, &mined.hash(), generate_timestamp());
+        seal_block(&blockchain, &mut child);
+        blockchain.insert(&child).unwrap();
+        let mut grandchild = child_block(&blockchain, &child.hash(), generate_timestamp());
+        seal_block(&blockchain, &mut grandchild);
+        stats.record_chain_update(&blockchain.insert(&grandchild).unwrap());
+        assert_eq!(blockchain.tip(), grandchild.hash());
+        assert_eq!(stats.report(now).stale_blocks, 0);
+    }
+
+    #[test]
+    fn only_recent_blocks_are_kept() {
+        let mut stats = Stats::new(Instant::now());
+        for i in 0..RECENT_BLOCKS + 10 {
+            stats.record_block([i as u8; 32].into());
+        }
+        assert_eq!(stats.recent.len(), RECENT_BLOCKS);
+        assert_eq!(stats.report(Instant::now()).blocks_found, (RECENT_BLOCKS + 10) as u64);
+    }
+}
@@ -1,18 +1,12 @@
-use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
-use log::{debug, info};
-use std::{
-    sync::{Arc, Mutex},
-    thread,
-};
+use crossbeam::channel::Receiver;
+use log::info;
+use std::thread;
 use crate::{
-    blockchain::Blockchain,
     network::server::Handle as ServerHandle,
     network::message::Message,
     types::{
         hash::Hashable,
         block::Block,
-        transaction::SignedTransaction,
-        mempool::Mempool,
     },
 };
 
@@ -20,19 +14,16 @@ use crate::{
 pub struct Worker {
     server: ServerHandle,
     finished_block_chan: Receiver<Block>,
-    blockchain: Arc<Mutex<Blockchain>>
 }
 
 impl Worker {
     pub fn new(
         server: &ServerHandle,
         finished_block_chan: Receiver<Block>,
-        blockchain: &Arc<Mutex<Blockchain>>
     ) -> Self {
         Self {
             server: server.clone(),
-            finished_block_chan: finished_block_chan,
-            blockchain: Arc::clone(blockchain)
+            finished_block_chan,
         }
     }
 
@@ -48,18 +39,8 @@ impl Worker {
 
     fn worker_loop(&self) {
         loop {
-            // Receive block from channel
+            // Receive block from channel; the miner has already inserted it into blockchain
             let block = self.finished_block_chan.recv().expect("Receive finished block error");
-            
-            // Insert this block into blockchain
-            let mut blockchain = self.blockchain.lock().unwrap();
-            let result = blockchain.insert(&block);
-            drop(blockchain);
-            
-            match result {
-                Ok(_) => println!("SUCCESS - inserted block into blockchain"),
-                Err(e) => panic!("{}", e)
-            }
 
             // Broadcast block hash as a NewBlockHashes message
             let hash = vec![block.hash()];
@@ -58,7 +58,7 @@ impl Context {
         })
             .detach();
         thread::spawn(move || smol::block_on(ex.run(futures::future::pending::<()>())));
-        return Ok(());
+        Ok(())
     }
 
     /// the loop that endlessly accept incoming peers
@@ -105,7 +105,7 @@ impl Context {
                 }
             }
         }
-        return Ok(());
+        Ok(())
     }
 
     /// Connect to a peer, and register this peer
@@ -115,7 +115,7 @@ impl Context {
         ex: Arc<Executor<'_>>,
     ) -> std::io::Result<peer::Handle> {
         debug!("Establishing connection to peer {}", addr);
-        let stream = Async::<std::net::TcpStream>::connect(addr.clone()).await?;
+        let stream = Async::<std::net::TcpStream>::connect(*addr).await?;
 
         // register the new peer
         self.register(stream, peer::Direction::Outgoing, ex).await
@@ -152,14 +152,9 @@ impl Context {
             let mut size_buffer: [u8; 4] = [0; 4];
             // the buffer to store the message content
             let mut msg_buffer: Vec<u8> = vec![];
-            loop {
-                // first, read exactly 4 bytes to get the frame header
-                let msg_size = match reader.read_exact(&mut size_buffer).await {
-                    Ok(_) => u32::from_be_bytes(size_buffer),
-                    Err(_) => {
-                        break;
-                    }
-                };
+            // first, read exactly 4 bytes to get the frame header
+            while let Ok(()) = reader.read_exact(&mut size_buffer).await {
+                let msg_size = u32::from_be_bytes(size_buffer);
                 // then, read exactly msg_size bytes to get the whole message
                 if msg_buffer.len() < msg_size as usize {
                     msg_buffer.resize(msg_size as usize, 0);
@@ -232,11 +227,11 @@ impl Context {
 pub struct Handle {
     control_chan: smol::channel::Sender<ControlSignal>,
 }
-#[cfg(any(test,test_utilities))]
+#[cfg(any(test, feature = "test-utilities"))]
 pub struct TestReceiver{
     control_chan: smol::channel::Receiver<ControlSignal>,
 }
-#[cfg(any(test,test_utilities))]
+#[cfg(any(test, feature = "test-utilities"))]
 impl TestReceiver {
     pub fn recv(&self) -> Option<message::Message> {
         let sig = smol::block_on(self.control_chan.recv()).unwrap();
@@ -267,7 +262,7 @@ impl Handle {
         smol::block_on(self.control_chan.send(ControlSignal::SendToPeer((receiver, msg)))).unwrap();
     }
 
-    #[cfg(any(test,test_utilities))]
+    #[cfg(any(test, feature = "test-utilities"))]
     pub fn new_for_test() -> (Handle, TestReceiver) {
         let (s,r) = smol::channel::unbounded();
         let h = Handle {control_chan: s};
@@ -4,10 +4,10 @@ use super::server::Handle as ServerHandle;
 use crate::types::{
     hash::{H256, Hashable},
     mempool::Mempool,
-    transaction,
     block::{Block},
 };
-use crate::blockchain::Blockchain;
+use crate::blockchain::{Blockchain, BlockError};
+use crate::miner::Handle as MinerHandle;
 use std::{
     sync::{Arc, Mutex},
     thread,
@@ -16,9 +16,9 @@ use std::collections::HashMap;
 use log::{debug, warn, error};
 
 
-#[cfg(any(test,test_utilities))]
+#[cfg(any(test, feature = "test-utilities"))]
 use super::peer::TestReceiver as PeerTestReceiver;
-#[cfg(any(test,test_utilities))]
+#[cfg(any(test, feature = "test-utilities"))]
 use super::server::TestReceiver as ServerTestReceiver;
 #[derive(Clone)]
 pub struct Worker {
@@ -26,7 +26,8 @@ pub struct Worker {
     num_worker: usize,
     server: ServerHandle,
     blockchain: Arc<Mutex<Blockchain>>,
-    mempool: Arc<Mutex<Mempool>>
+    mempool: Arc<Mutex<Mempool>>,
+    miner: MinerHandle,    // rebuilds its block template when the tip changes or a better transaction arrives
 }
 
 
@@ -36,14 +37,16 @@ impl Worker {
         msg_src: smol::channel::Receiver<(Vec<u8>, peer::Handle)>,
         server: &ServerHandle,
         blockchain: &Arc<Mutex<Blockchain>>,
-        mempool: &Arc<Mutex<Mempool>>
+        mempool: &Arc<Mutex<Mempool>>,
+        miner: &MinerHandle
     ) -> Self {
         Self {
             msg_chan: msg_src,
             num_worker,
             server: server.clone(),
             blockchain: Arc::clone(blockchain),
-            mempool: Arc::clone(mempool)
+            mempool: Arc::clone(mempool),
+            miner: miner.clone()
         }
     }
 
@@ -90,10 +93,10 @@ impl Worker {
                         // Get the hash and check
                         let block_hash = blockchain.get_block(hash);
                         match block_hash {
-                            Ok(_) => {()}
+                            Ok(_) => {}
                             Err(_) => {
                                 // Add the hash to the unknown vector
-                                unknown.push(hash.clone());
+                                unknown.push(*hash);
                             }
                         }
                     }
@@ -113,11 +116,8 @@ impl Worker {
                     
                     for hash in hashes.iter() {
                         let result = blockchain.get_block(hash);
-                        match result {
-                            Ok(block) => {
-                                known.push(block.clone());
-                            }
-                            Err(_) => {()}
+                        if let Ok(block) = result {
+                            known.push(block.clone());
                         }
                     }
 
@@ -131,6 +131,7 @@ impl Worker {
                 // BLOCKS
                 Message::Blocks(blocks) => {
                     let mut blockchain = self.blockchain.lock().unwrap();
+                    let tip = blockchain.tip();
                     
                     let mut new_block_hashes = Vec::new();
                     let mut blocks = blocks.clone();
@@ -138,55 +139,57 @@ impl Worker {
                     let mut i = 0;
                     while i < blocks.len() {
                         let block = &blocks[i].clone();                 
-
-                        // Skip if block hash exceeds difficulty
-                        if block.hash() > block.get_difficulty() {
-                            continue;
-                        }
+                        i += 1;    // next block
 
                         // Skip if this block is already in blockchain
                         if blockchain.get_block(&block.hash()).is_ok() {
                             continue;
                         }
 
-                        // Attempt to insert this block into the blockchain
-                        match blockchain.insert(&block) {
+                        // Attempt to insert this block into the blockchain, which validates it
+                        match blockchain.insert(block) {
                             // Block was successfully inserted into blockchain
-                            Ok(_) => {
+                            Ok(update) => {
                                 new_block_hashes.push(block.hash());
                                 
-                                // Remove the block's transactions from mempool
+                                // Bring the mempool in line with the new main chain
                                 let mut mempool = self.mempool.lock().unwrap();
-                                for txn in block.content.transactions.iter() {
-                                    mempool.map.remove(&txn.hash());
-                                }
+                                mempool.update_chain(&blockchain, &update);
                                 drop(mempool);
+                                self.miner.chain_updated(&update);
                                 
                                 // Check if there are orphans whose parent is this block
                                 if let Some(orphans) = orphan_buffer.get(&block.hash()) {
                                     // This block is the parent to some orphans, so take them out 
                                     // of orphan_buffer and put them in line to be added to blockchain
-                                    blocks.extend_from_slice(&orphans);
+                                    blocks.extend_from_slice(orphans);
                                     orphan_buffer.remove(&block.hash());
                                 }
                             }
 
                             // Parent of the block is not in blockchain
-                            Err(true) => {
+                            Err(BlockError::Orphan { .. }) => {
+                                debug!("Buffering orphan block {}", block.hash());
                                 // Add block into the array of orphans corresponding to its parent
                                 orphan_buffer.entry(block.get_parent())
-                                             .or_insert_with(Vec::new).push(block.clone());
+                                             .or_default().push(block.clone());
 
                                 // Request missing blocks
                                 peer.write(Message::GetBlocks(vec![block.hash()]));
                             }
                             
-                            // Block did not pass transaction checks
-                            Err(false) => {}
+                            // Block did not pass validation
+                            Err(e) => {
+                                warn!("Rejected block {}: {}", block.hash(), e);
+                            }
                         }
+                    }
 
-                        i += 1;    // next block
+                    // The miner's block template no longer extends the longest chain
+                    if blockchain.tip() != tip {
+                        self.miner.update();
                     }
+                    drop(blockchain);
 
                     if !new_block_hashes.is_empty() {
                         self.server.broadcast(Message::NewBlockHashes(new_block_hashes));
@@ -198,9 +201,9 @@ impl Worker {
                     let mempool = self.mempool.lock().unwrap();
                     let mut unknown = Vec::new();
                     for hash in hashes.iter() {
-                        if !mempool.map.contains_key(hash) {
+                        if !mempool.contains(hash) {
                             // hash not in mempool, so add it to vec of unknowns
-                            unknown.push(hash.clone());   
+                            unknown.push(*hash);   
                         }
                     }
                     drop(mempool);
@@ -215,8 +218,7 @@ impl Worker {
                     let mempool = self.mempool.lock().unwrap();
                     let mut transactions = Vec::new();
                     for hash in hashes.iter() {
-                        if mempool.map.contains_key(hash) {
-                            let txn = mempool.map.get(hash).unwrap();
+                        if let Some(txn) = mempool.get(hash) {
                             transactions.push(txn.clone());
                         }
                     }
@@ -231,18 +233,27 @@ impl Worker {
                 Message::Transactions(transactions) => {
                     let mut mempool = self.mempool.lock().unwrap();
                     let mut new_hashes = Vec::new();
+                    let mut improves_template = false;
                     for txn in transactions.iter() {
-                        if !mempool.map.contains_key(&txn.hash()) {
-                            // check current transaction
-                            if transaction::verify(&txn.transaction, &txn.public_key, &txn.signature) {
-                                // passed check; insert transaction into mempool
-                                mempool.map.insert(txn.hash(), txn.clone());
+                        // relay it only if the mempool accepts it, which checks its signature,
+                        // and never relay a transaction it replaced
+                        match mempool.insert(txn.clone()) {
+                            Ok(inserted) => {
+                                if let Some(replaced) = inserted.replaced {
+                                    debug!("Transaction {} replaced {}", txn.hash(), replaced);
+                                }
+                                improves_template |= inserted.improves_template;
                                 new_hashes.push(txn.hash());
-                            }                            
+                            }
+                            Err(e) => debug!("Transaction {} not added to mempool: {}", txn.hash(), e),
                         }
                     }
                     drop(mempool);
 
+                    if improves_template {
+                        self.miner.update();
+                    }
+
                     if !new_hashes.is_empty() {
                         self.server.broadcast(Message::NewTransactionHashes(new_hashes));
                     }
@@ -252,11 +263,11 @@ impl Worker {
     }
 }
 
-#[cfg(any(test,test_utilities))]
+#[cfg(any(test, feature = "test-utilities"))]
 struct TestMsgSender {
     s: smol::channel::Sender<(Vec<u8>, peer::Handle)>
 }
-#[cfg(any(test,test_utilities))]
+#[cfg(any(test, feature = "test-utilities"))]
 impl TestMsgSender {
     fn new() -> (TestMsgSender, smol::channel::Receiver<(Vec<u8>, peer::Handle)>) {
         let (s,r) = smol::channel::unbounded();
@@ -271,14 +282,17 @@ impl TestMsgSender {
     }
 }
 
-#[cfg(any(test,test_utilities))]
+#[cfg(any(test, feature = "test-utilities"))]
 /// returns two structs used by tests, and an ordered vector of hashes of all blocks in the blockchain
 fn generate_test_worker_and_start() -> (TestMsgSender, ServerTestReceiver, Vec<H256>) {
     let (server, server_receiver) = ServerHandle::new_for_test();
     let (test_msg_sender, msg_chan) = TestMsgSender::new();
-    let blockchain = Blockchain::new();
+    let blockchain = Blockchain::with_params(crate::blockchain::test_params());
     let blockchain = Arc::new(Mutex::new(blockchain));
-    let worker = Worker::new(1, msg_chan, &server, &blockchain);
+    let mempool = Arc::new(Mutex::new(Mempool::new()));
+    let (miner_ctx, miner, _) = crate::miner::new(&blockchain, &mempool, Default::default(), 1);
+    miner_ctx.start();    // stays paused, ignoring the updates it is sent
+    let worker = Worker::new(1, msg_chan, &server, &blockchain, &mempool, &miner);
     worker.start(); 
 
     let current_chain = blockchain.lock().unwrap();
@@ -291,6 +305,7 @@ fn generate_test_worker_and_start() -> (TestMsgSender, ServerTestReceiver, Vec<H
 #[cfg(test)]
 mod test {
     use ntest::timeout;
+    use crate::blockchain::{test_params, Blockchain};
     use crate::types::block::generate_random_block;
     use crate::types::hash::Hashable;
 
@@ -301,7 +316,7 @@ mod test {
     #[timeout(60000)]
     fn reply_new_block_hashes() {
         let (test_msg_sender, _server_receiver, v) = generate_test_worker_and_start();
-        let random_block = generate_random_block(v.last().unwrap());
+        let random_block = generate_random_block(&Blockchain::with_params(test_params()), v.last().unwrap());
         let mut peer_receiver = test_msg_sender.send(Message::NewBlockHashes(vec![random_block.hash()]));
         let reply = peer_receiver.recv();
         if let Message::GetBlocks(v) = reply {
@@ -314,8 +329,8 @@ mod test {
     #[timeout(60000)]
     fn reply_get_blocks() {
         let (test_msg_sender, _server_receiver, v) = generate_test_worker_and_start();
-        let h = v.last().unwrap().clone();
-        let mut peer_receiver = test_msg_sender.send(Message::GetBlocks(vec![h.clone()]));
+        let h = *v.last().unwrap();
+        let mut peer_receiver = test_msg_sender.send(Message::GetBlocks(vec![h]));
         let reply = peer_receiver.recv();
         if let Message::Blocks(v) = reply {
             assert_eq!(1, v.len());
@@ -328,7 +343,7 @@ mod test {
     #[timeout(60000)]
     fn reply_blocks() {
         let (test_msg_sender, server_receiver, v) = generate_test_worker_and_start();
-        let random_block = generate_random_block(v.last().unwrap());
+        let random_block = generate_random_block(&Blockchain::with_params(test_params()), v.last().unwrap());
         let mut _peer_receiver = test_msg_sender.send(Message::Blocks(vec![random_block.clone()]));
         let reply = server_receiver.recv().unwrap();
         if let Message::NewBlockHashes(v) = reply {
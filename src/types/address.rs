@@ -19,6 +19,12 @@ impl std::convert::From<[u8; 20]> for Address {
     }
 }
 
+impl std::convert::AsRef<[u8]> for Address {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
 impl std::fmt::Display for Address {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         let start = if let Some(precision) = f.precision() {
@@ -47,6 +53,20 @@ impl std::fmt::Debug for Address {
     }
 }
 
+impl std::str::FromStr for Address {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Address, String> {
+        let bytes = hex::decode(s).map_err(|e| e.to_string())?;
+        if bytes.len() != 20 {
+            return Err(format!("expected 20 bytes, got {}", bytes.len()));
+        }
+        let mut address_bytes: [u8; 20] = [0; 20];
+        address_bytes.copy_from_slice(&bytes);
+        Ok(Address(address_bytes))
+    }
+}
+
 impl Address {
     pub fn from_public_key_bytes(bytes: &[u8]) -> Address {
         // Hash the input bytes using SHA-256
@@ -66,6 +86,15 @@ impl Address {
         self.0.iter().map(|byte| format!("{:02x}", byte)).collect()
     }
 }
+#[cfg(any(test, feature = "test-utilities"))]
+pub fn generate_random_address() -> Address {
+    use rand::Rng;
+    let mut rng = rand::thread_rng();
+    let mut bytes = [0u8; 20];
+    rng.fill(&mut bytes);
+    Address(bytes)
+}
+
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
 
 #[cfg(test)]
@@ -82,6 +111,15 @@ mod test {
         // "0a0b0c0d0e0f0e0d0a0b0c0d0e0f0e0d0a0b0c0d0e0f0e0d0a0b0c0d0e0f0e0d"
         // take the last 20 bytes, we get "1851a0eae0060a132cf0f64a0ffaea248de6cba0"
     }
+
+    #[test]
+    fn from_hex_string() {
+        let addr: Address = hex!("1851a0eae0060a132cf0f64a0ffaea248de6cba0").into();
+        assert_eq!("1851a0eae0060a132cf0f64a0ffaea248de6cba0".parse::<Address>(), Ok(addr));
+        assert_eq!(addr.to_hex_string().parse::<Address>(), Ok(addr));
+        assert!("1851a0ea".parse::<Address>().is_err());
+        assert!("not hex".parse::<Address>().is_err());
+    }
 }
 
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
\ No newline at end of file
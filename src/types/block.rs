@@ -1,9 +1,8 @@
 use crate::types::{
     hash::{H256, Hashable},
-    transaction::SignedTransaction,
+    transaction::{CoinbaseTransaction, SignedTransaction},
     merkle::MerkleTree,
 };
-use rand::Rng;
 use bincode;
 use serde::{Serialize, Deserialize};
 
@@ -22,14 +21,31 @@ pub struct Header {
     pub difficulty: H256,
     pub timestamp: u128,
     pub merkle_root: H256,
+    pub state_root: H256,
 }
 
-// A Content, containing the transactions data of a block
+// A Content, containing the coinbase and transactions data of a block
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Content {
+    pub coinbase: CoinbaseTransaction,
     pub transactions: Vec<SignedTransaction>
 }
 
+// Implement the merkle commitment for Content
+impl Content {
+    /// Merkle tree over the coinbase followed by the transactions
+    pub fn merkle_tree(&self) -> MerkleTree {
+        let mut leaves = Vec::with_capacity(self.transactions.len() + 1);
+        leaves.push(self.coinbase.hash());
+        leaves.extend(self.transactions.iter().map(|txn| txn.hash()));
+        MerkleTree::from_leaves(&leaves)
+    }
+
+    pub fn merkle_root(&self) -> H256 {
+        self.merkle_tree().root()
+    }
+}
+
 // Implement the hash function for Header
 impl Hashable for Header {
     fn hash(&self) -> H256 {
@@ -45,6 +61,35 @@ impl Hashable for Block {
     }
 }
 
+// Offsets of the fields bincode lays out before the nonce and the timestamp
+const NONCE_OFFSET: usize = 32;               // parent
+const TIMESTAMP_OFFSET: usize = 32 + 4 + 32;  // parent, nonce, difficulty
+
+/// A serialized Header whose nonce and timestamp are rewritten in place, so that
+/// proof-of-work attempts hash the same bytes as `Header::hash` without re-serializing
+#[derive(Debug, Clone)]
+pub struct HeaderBuffer {
+    bytes: Vec<u8>,
+}
+
+impl HeaderBuffer {
+    pub fn new(header: &Header) -> Self {
+        HeaderBuffer { bytes: bincode::serialize(header).unwrap() }
+    }
+
+    pub fn set_nonce(&mut self, nonce: u32) {
+        self.bytes[NONCE_OFFSET..NONCE_OFFSET + 4].copy_from_slice(&nonce.to_le_bytes());
+    }
+
+    pub fn set_timestamp(&mut self, timestamp: u128) {
+        self.bytes[TIMESTAMP_OFFSET..TIMESTAMP_OFFSET + 16].copy_from_slice(&timestamp.to_le_bytes());
+    }
+
+    pub fn hash(&self) -> H256 {
+        ring::digest::digest(&ring::digest::SHA256, &self.bytes).into()
+    }
+}
+
 // Implement getter functions for Block
 impl Block {
     pub fn get_parent(&self) -> H256 {
@@ -58,28 +103,62 @@ impl Block {
 
 //------------------------------------------------------------------------------------
 
-// Generate a random Block to help test the Blockchain implementation
-#[cfg(any(test, test_utilities))]
-pub fn generate_random_block(parent: &H256) -> Block {
+// Generate a random valid Block with no transactions on `parent`, which must be in
+// `blockchain`, to help test the Blockchain implementation
+#[cfg(any(test, feature = "test-utilities"))]
+pub fn generate_random_block(blockchain: &crate::blockchain::Blockchain, parent: &H256) -> Block {
+    use rand::Rng;
+
     let mut rng = rand::thread_rng();  // create a random number generator
     let nonce: u32 = rng.gen();        // make nonce a random integer
 
-    let difficulty = H256::default();       // use default difficulty
-    let timestamp = rng.gen::<u128>();      // use current time
+    let height = blockchain.get_height(parent).expect("Parent does not exist in blockchain.") + 1;
+    let difficulty = blockchain.next_difficulty(parent).unwrap();   // use the difficulty the chain expects
+    let timestamp = generate_timestamp();   // use current time
 
-    let transactions: Vec<SignedTransaction> = Vec::new();  // empty transactions vector
-    let merkle_tree = MerkleTree::new(&transactions);       // empty merkle tree
-    let merkle_root = merkle_tree.root();
+    // Pay the block subsidy to a random address
+    let coinbase = CoinbaseTransaction {
+        height,
+        receiver: crate::types::address::generate_random_address(),
+        value: blockchain.params().block_subsidy(height),
+        extra_nonce: 0
+    };
 
-    let content = Content{ transactions };      // content with empty transactions
+    let transactions: Vec<SignedTransaction> = Vec::new();  // empty transactions vector
+    let content = Content{ coinbase, transactions };        // content with empty transactions
+    let merkle_root = content.merkle_root();
+    let state_root = blockchain.state_root_after(parent, &content).unwrap();
     
     let header = Header {
         parent: *parent,
-        nonce: nonce,
-        difficulty: difficulty,
-        timestamp: timestamp,
-        merkle_root: merkle_root
+        nonce,
+        difficulty,
+        timestamp,
+        merkle_root,
+        state_root
     };
 
-    Block{ header, content }
-}
\ No newline at end of file
+    let mut block = Block{ header, content };
+    solve_block(&mut block);
+    block
+}
+
+// Current time in milliseconds, strictly increasing across calls so that
+// consecutive test blocks always pass the median-time-past check
+#[cfg(any(test, feature = "test-utilities"))]
+pub fn generate_timestamp() -> u128 {
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::time::{SystemTime, UNIX_EPOCH};
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
+    now + COUNTER.fetch_add(1, Ordering::Relaxed) as u128
+}
+
+// Increment the nonce of a Block until its hash meets its difficulty
+#[cfg(any(test, feature = "test-utilities"))]
+pub fn solve_block(block: &mut Block) {
+    while block.hash() > block.get_difficulty() {
+        block.header.nonce = block.header.nonce.wrapping_add(1);
+    }
+}
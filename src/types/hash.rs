@@ -1,6 +1,6 @@
 use serde::{Serialize, Deserialize};
 use std::convert::TryInto;
-#[cfg(any(test, test_utilities))]
+#[cfg(any(test, feature = "test-utilities"))]
 use rand::Rng;
 
 /// An object that can be meaningfully hashed.
@@ -47,6 +47,20 @@ impl std::fmt::Debug for H256 {
     }
 }
 
+impl std::str::FromStr for H256 {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<H256, String> {
+        let bytes = hex::decode(s).map_err(|e| e.to_string())?;
+        if bytes.len() != 32 {
+            return Err(format!("expected 32 bytes, got {}", bytes.len()));
+        }
+        let mut hash_bytes: [u8; 32] = [0; 32];
+        hash_bytes.copy_from_slice(&bytes);
+        Ok(H256(hash_bytes))
+    }
+}
+
 impl std::convert::AsRef<[u8]> for H256 {
     fn as_ref(&self) -> &[u8] {
         &self.0
@@ -109,7 +123,7 @@ impl PartialOrd for H256 {
     }
 }
 
-#[cfg(any(test, test_utilities))]
+#[cfg(any(test, feature = "test-utilities"))]
 pub fn generate_random_hash() -> H256 {
     let mut rng = rand::thread_rng();
     let random_bytes: Vec<u8> = (0..32).map(|_| rng.gen()).collect();
@@ -1,18 +1,819 @@
 use super::{
+    address::Address,
     hash::{Hashable, H256},
-    transaction::SignedTransaction,
+    state::State,
+    transaction::{self, SignedTransaction},
 };
-use std::collections::HashMap;
+use crate::blockchain::{self, Blockchain, ChainUpdate, TransactionError};
+use serde::Serialize;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::time::{Duration, Instant};
 
+/// Limits and relay policy of a Mempool
+#[derive(Debug, Clone)]
+pub struct MempoolConfig {
+    pub max_transactions: usize,
+    pub max_bytes: usize,          // total serialized size of the pooled transactions
+    pub min_relay_fee: u128,       // smallest fee a transaction must pay to be accepted
+    pub expiry: Duration,          // how long a transaction may wait before it is dropped
+    pub replacement_fee_bump: u128,    // percentage by which a replacement must raise the fee
+}
+
+impl Default for MempoolConfig {
+    fn default() -> Self {
+        MempoolConfig {
+            max_transactions: 10_000,
+            max_bytes: 10_000_000,
+            min_relay_fee: 1,
+            expiry: Duration::from_secs(60 * 60),
+            replacement_fee_bump: 10,
+        }
+    }
+}
+
+/// The reason a transaction was not added to the Mempool
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MempoolError {
+    Duplicate,
+    InvalidSignature,
+    StaleNonce { account_nonce: u128, found: u128 },
+    ReplacementFeeTooLow { fee: u128, required: u128 },
+    FeeTooLow { fee: u128, min: u128 },
+    Full,
+}
+
+impl MempoolError {
+    /// Short machine-readable name of the rule that failed
+    pub fn kind(&self) -> &'static str {
+        match self {
+            MempoolError::Duplicate => "duplicate",
+            MempoolError::InvalidSignature => "invalid-signature",
+            MempoolError::StaleNonce { .. } => "stale-nonce",
+            MempoolError::ReplacementFeeTooLow { .. } => "replacement-fee-too-low",
+            MempoolError::FeeTooLow { .. } => "fee-too-low",
+            MempoolError::Full => "mempool-full",
+        }
+    }
+}
+
+impl fmt::Display for MempoolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MempoolError::Duplicate => write!(f, "transaction already in mempool"),
+            MempoolError::InvalidSignature => write!(f, "invalid signature"),
+            MempoolError::StaleNonce { account_nonce, found } => {
+                write!(f, "account nonce {} was already used, account is at {}", found, account_nonce)
+            }
+            MempoolError::ReplacementFeeTooLow { fee, required } => {
+                write!(f, "fee {} is too low to replace the pending transaction, requires {}", fee, required)
+            }
+            MempoolError::FeeTooLow { fee, min } => {
+                write!(f, "fee {} is below the minimum relay fee {}", fee, min)
+            }
+            MempoolError::Full => write!(f, "mempool is full"),
+        }
+    }
+}
+
+impl std::error::Error for MempoolError {}
+
+/// Whether a pending transaction could be mined on top of the current tip
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TransactionStatus {
+    Ready,      // valid in sequence after the sender's earlier pending transactions
+    Future,     // waits for a missing nonce or for the sender to afford it
+}
+
+/// Counters describing the Mempool, reported by the API
+#[derive(Serialize, Debug, Default, Clone, Copy)]
+pub struct MempoolStats {
+    pub transactions: usize,
+    pub ready: usize,
+    pub future: usize,
+    pub bytes: usize,
+    pub evicted: u64,              // removed to make room for better paying transactions
+    pub replaced: u64,             // replaced by a transaction with the same nonce and a higher fee
+    pub expired: u64,              // removed after waiting longer than the expiry time
+    pub invalidated: u64,          // removed after the main chain used their nonce
+    pub rejected_low_fee: u64,     // refused for paying less than the minimum relay fee
+    pub rejected_full: u64,        // refused for paying too little to displace anything
+}
+
+/// Compare the fee per byte of two transactions
+pub fn compare_fee_rate(fee: u128, size: usize, other_fee: u128, other_size: usize) -> Ordering {
+    fee.saturating_mul(other_size as u128).cmp(&other_fee.saturating_mul(size as u128))
+}
+
+// A pooled transaction along with the information used to evict it
+#[derive(Debug, Clone)]
+struct Entry {
+    txn: SignedTransaction,
+    size: usize,
+    received: Instant,
+    status: TransactionStatus,
+}
+
+// Orders pooled transactions for eviction: lowest fee rate first, then oldest
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct EvictionKey {
+    fee: u128,
+    size: usize,
+    received: Instant,
+    hash: H256,
+}
+
+impl EvictionKey {
+    fn new(hash: H256, entry: &Entry) -> Self {
+        EvictionKey { fee: entry.txn.transaction.fee, size: entry.size, received: entry.received, hash }
+    }
+}
+
+impl Ord for EvictionKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        compare_fee_rate(self.fee, self.size, other.fee, other.size)
+            .then(self.received.cmp(&other.received))
+            .then(self.hash.cmp(&other.hash))
+    }
+}
+
+impl PartialOrd for EvictionKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Outcome of adding a transaction to a Mempool
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Inserted {
+    pub replaced: Option<H256>,    // pending transaction with the same sender and nonce
+    pub improves_template: bool,   // whether the miner should rebuild its block template
+}
+
+// A Mempool holds the transactions waiting to be included in a block, indexed by hash
+// and, for each sender, by account nonce
 #[derive(Debug, Default, Clone)]
 pub struct Mempool {
-    pub map: HashMap<H256, SignedTransaction>
+    config: MempoolConfig,
+    by_hash: HashMap<H256, Entry>,
+    by_sender: HashMap<Address, BTreeMap<u128, H256>>,    // <sender, <account nonce, txn hash>>
+    by_arrival: BTreeSet<(Instant, H256)>,
+    by_eviction: BTreeSet<EvictionKey>,    // next to evict first
+    tip_state: State,    // state of the main chain's tip, which entries are classified against
+    bytes: usize,
+    stats: MempoolStats,
+    template_floor: Option<(u128, usize)>,    // (fee, size) paying the lowest fee rate in the template
 }
 
 impl Mempool {
     pub fn new() -> Self {
+        Self::with_config(MempoolConfig::default())
+    }
+
+    pub fn with_config(config: MempoolConfig) -> Self {
         Self{
-            map: HashMap::new()
+            config,
+            by_hash: HashMap::new(),
+            by_sender: HashMap::new(),
+            by_arrival: BTreeSet::new(),
+            by_eviction: BTreeSet::new(),
+            tip_state: State::new(),
+            bytes: 0,
+            stats: MempoolStats::default(),
+            template_floor: None
+        }
+    }
+
+    pub fn config(&self) -> &MempoolConfig {
+        &self.config
+    }
+
+    /// Record the fee and size of the lowest fee rate transaction in the block template,
+    /// or None if the template holds every ready transaction and any other would improve it
+    pub fn set_template_floor(&mut self, floor: Option<(u128, usize)>) {
+        self.template_floor = floor;
+    }
+
+    /// Smallest fee a transaction must pay to replace a pending one paying `fee`
+    pub fn replacement_fee(&self, fee: u128) -> u128 {
+        let bump = (fee.saturating_mul(self.config.replacement_fee_bump) / 100).max(1);
+        fee.saturating_add(bump)
+    }
+
+    /// Add a transaction, returning the hash of the pending transaction it replaced, if any, and
+    /// whether it is ready and pays more per byte than the template's floor. A transaction
+    /// with the same sender and nonce as a pending one replaces it only if its fee is at least
+    /// the `replacement_fee` of the pending one. Expired transactions are dropped first, and if
+    /// the pool is then over its limits the lowest fee rate transactions are evicted, oldest
+    /// first, together with the later nonces of their senders which could no longer be mined.
+    /// A transaction that would be evicted itself is refused and leaves the pool untouched.
+    pub fn insert(&mut self, txn: SignedTransaction) -> Result<Inserted, MempoolError> {
+        let now = Instant::now();
+        self.expire(now);
+
+        let hash = txn.hash();
+        if self.by_hash.contains_key(&hash) {
+            return Err(MempoolError::Duplicate);
+        }
+        if !transaction::verify(&txn.transaction, &txn.public_key, &txn.signature) {
+            return Err(MempoolError::InvalidSignature);
+        }
+        if txn.transaction.fee < self.config.min_relay_fee {
+            self.stats.rejected_low_fee += 1;
+            return Err(MempoolError::FeeTooLow { fee: txn.transaction.fee, min: self.config.min_relay_fee });
+        }
+
+        // A nonce the tip has already used can never be mined
+        let sender = Address::from_public_key_bytes(&txn.public_key);
+        let nonce = txn.transaction.account_nonce;
+        let account_nonce = self.tip_state.get(&sender).map_or(0, |info| info.0);
+        if nonce <= account_nonce {
+            return Err(MempoolError::StaleNonce { account_nonce, found: nonce });
+        }
+
+        // The pending transaction with the same nonce is replaced if this one pays enough more
+        let replaced = self.by_sender.get(&sender).and_then(|nonces| nonces.get(&nonce)).copied();
+        if let Some(pending_hash) = replaced {
+            let required = self.replacement_fee(self.by_hash[&pending_hash].txn.transaction.fee);
+            if txn.transaction.fee < required {
+                return Err(MempoolError::ReplacementFeeTooLow { fee: txn.transaction.fee, required });
+            }
+        }
+
+        // Refuse the transaction before touching the pool if making room would drop it
+        let size = bincode::serialized_size(&txn).unwrap() as usize;
+        let entry = Entry { txn, size, received: now, status: TransactionStatus::Future };
+        let key = EvictionKey::new(hash, &entry);
+        let victims = match self.eviction_plan(&key, &sender, nonce, replaced.as_ref()) {
+            Some(victims) => victims,
+            None => {
+                self.stats.rejected_full += 1;
+                return Err(MempoolError::Full);
+            }
+        };
+
+        if let Some(pending_hash) = replaced {
+            self.remove(&pending_hash);
+            self.stats.replaced += 1;
+        }
+        self.by_sender.entry(sender).or_default().insert(nonce, hash);
+        self.by_arrival.insert((now, hash));
+        self.by_eviction.insert(key);
+        self.by_hash.insert(hash, entry);
+        self.bytes += size;
+        for victim in victims.iter() {
+            self.remove(victim);
+        }
+        self.stats.evicted += victims.len() as u64;
+        self.classify_sender(&sender);
+        Ok(Inserted { replaced, improves_template: self.improves_template(&hash) })
+    }
+
+    /// Remove a transaction by hash, returning it if it was present
+    pub fn remove(&mut self, hash: &H256) -> Option<SignedTransaction> {
+        let entry = self.by_hash.remove(hash)?;
+        self.by_arrival.remove(&(entry.received, *hash));
+        self.by_eviction.remove(&EvictionKey::new(*hash, &entry));
+        self.bytes -= entry.size;
+
+        let sender = Address::from_public_key_bytes(&entry.txn.public_key);
+        if let Some(nonces) = self.by_sender.get_mut(&sender) {
+            nonces.remove(&entry.txn.transaction.account_nonce);
+            if nonces.is_empty() {
+                self.by_sender.remove(&sender);
+            }
+        }
+        Some(entry.txn)
+    }
+
+    // Remove a transaction and every later nonce of its sender
+    fn remove_with_descendants(&mut self, hash: &H256) -> Vec<SignedTransaction> {
+        let txn = match self.by_hash.get(hash) {
+            Some(entry) => &entry.txn,
+            None => return Vec::new(),
+        };
+        let sender = Address::from_public_key_bytes(&txn.public_key);
+        let hashes: Vec<H256> = self.by_sender[&sender]
+            .range(txn.transaction.account_nonce..)
+            .map(|(_, hash)| *hash)
+            .collect();
+        hashes.iter().filter_map(|hash| self.remove(hash)).collect()
+    }
+
+    // The transactions to evict, lowest fee rate then oldest first, each with the later nonces
+    // of its sender which could no longer be mined, for the pool to stay within its limits once
+    // the transaction with eviction key `new` is added in place of `replaced`. Returns None if
+    // the new transaction would have to be evicted itself.
+    fn eviction_plan(&self, new: &EvictionKey, sender: &Address, nonce: u128, replaced: Option<&H256>) -> Option<Vec<H256>> {
+        let replaced_size = replaced.map_or(0, |hash| self.by_hash[hash].size);
+        let mut count = self.by_hash.len() + 1 - replaced.map_or(0, |_| 1);
+        let mut bytes = self.bytes - replaced_size + new.size;
+        let mut victims = Vec::new();
+        let mut dropped = HashSet::new();
+
+        let mut candidates = self.by_eviction.iter().filter(|key| Some(&key.hash) != replaced);
+        while count > self.config.max_transactions || bytes > self.config.max_bytes {
+            let key = match candidates.next() {
+                Some(key) if dropped.contains(&key.hash) => continue,
+                Some(key) if key < new => key,
+                _ => return None,
+            };
+            let victim = &self.by_hash[&key.hash].txn;
+            let victim_sender = Address::from_public_key_bytes(&victim.public_key);
+            let victim_nonce = victim.transaction.account_nonce;
+            if victim_sender == *sender && victim_nonce < nonce {
+                return None;
+            }
+            for (_, hash) in self.by_sender[&victim_sender].range(victim_nonce..) {
+                if Some(hash) != replaced && dropped.insert(*hash) {
+                    count -= 1;
+                    bytes -= self.by_hash[hash].size;
+                    victims.push(*hash);
+                }
+            }
+        }
+        Some(victims)
+    }
+
+    /// Follow a change of the main chain: transactions of connected blocks are removed, the
+    /// rest are revalidated against the new tip, and those of disconnected blocks are added
+    /// back unless the new tip already used their nonce.
+    pub fn update_chain(&mut self, blockchain: &Blockchain, update: &ChainUpdate) {
+        if update.is_empty() {
+            return;
+        }
+
+        for hash in update.connected.iter() {
+            for txn in blockchain.get_block(hash).unwrap().content.transactions.iter() {
+                self.remove(&txn.hash());
+            }
+        }
+        self.revalidate(blockchain.get_state(&blockchain.tip()).unwrap());
+
+        // Oldest block first, so each sender's nonces come back in order
+        for hash in update.disconnected.iter().rev() {
+            for txn in blockchain.get_block(hash).unwrap().content.transactions.iter() {
+                let _ = self.insert(txn.clone());
+            }
+        }
+    }
+
+    /// Classify every transaction against the state of a new tip, dropping only those
+    /// whose nonce it has already used
+    pub fn revalidate(&mut self, tip_state: &State) {
+        self.tip_state = tip_state.clone();
+        let senders: Vec<Address> = self.by_sender.keys().copied().collect();
+        for sender in senders {
+            for hash in self.classify_sender(&sender) {
+                self.remove(&hash);
+                self.stats.invalidated += 1;
+            }
+        }
+    }
+
+    // Whether a pending transaction is ready and pays more per byte than the template's floor
+    fn improves_template(&self, hash: &H256) -> bool {
+        let entry = &self.by_hash[hash];
+        entry.status == TransactionStatus::Ready && match self.template_floor {
+            Some((fee, size)) => compare_fee_rate(entry.txn.transaction.fee, entry.size, fee, size) == Ordering::Greater,
+            None => true,
+        }
+    }
+
+    // Classify the transactions of `sender` against the tip state, returning the invalid ones.
+    // Transactions are ready while they continue the account nonce and the sender can afford
+    // them after the ones before; everything after a gap or an unaffordable one waits.
+    fn classify_sender(&mut self, sender: &Address) -> Vec<H256> {
+        let nonces = match self.by_sender.get(sender) {
+            Some(nonces) => nonces,
+            None => return Vec::new(),
+        };
+        let (account_nonce, mut balance, mut ready) = match self.tip_state.get(sender) {
+            Some((nonce, balance)) => (nonce, balance, true),
+            None => (0, 0, false),    // account may still be created by an incoming payment
+        };
+
+        let mut invalid = Vec::new();
+        let mut statuses = Vec::new();
+        let mut next_nonce = account_nonce + 1;
+        for (nonce, hash) in nonces.iter() {
+            if *nonce <= account_nonce {
+                invalid.push(*hash);
+                continue;
+            }
+            let txn = &self.by_hash[hash].txn.transaction;
+            let cost = txn.value.saturating_add(txn.fee);
+            ready = ready && *nonce == next_nonce && cost <= balance;
+            if ready {
+                next_nonce += 1;
+                balance -= cost;
+                statuses.push((*hash, TransactionStatus::Ready));
+            } else {
+                statuses.push((*hash, TransactionStatus::Future));
+            }
+        }
+
+        for (hash, status) in statuses {
+            self.by_hash.get_mut(&hash).unwrap().status = status;
+        }
+        invalid
+    }
+
+    /// Check a transaction against the tip state as it will be once the sender's ready
+    /// transactions with lower nonces are mined, so that it could be mined right after them
+    pub fn validate(&self, txn: &SignedTransaction) -> Result<(), TransactionError> {
+        let sender = Address::from_public_key_bytes(&txn.public_key);
+        let mut state = State::new();
+        if let Some((mut account_nonce, mut balance)) = self.tip_state.get(&sender) {
+            for (nonce, hash) in self.by_sender.get(&sender).into_iter().flatten() {
+                let entry = &self.by_hash[hash];
+                if *nonce >= txn.transaction.account_nonce || entry.status != TransactionStatus::Ready {
+                    break;
+                }
+                account_nonce = *nonce;
+                let required = entry.txn.transaction.value.checked_add(entry.txn.transaction.fee);
+                balance = match required.and_then(|required| balance.checked_sub(required)) {
+                    Some(balance) => balance,
+                    None => return Err(TransactionError::InsufficientBalance {
+                        balance,
+                        required: required.unwrap_or(u128::MAX)
+                    }),
+                };
+            }
+            state.insert(sender, (account_nonce, balance));
+        }
+        blockchain::validate_transaction(&state, txn)
+    }
+
+    /// Whether a pending transaction is ready to be mined on the current tip
+    pub fn status(&self, hash: &H256) -> Option<TransactionStatus> {
+        self.by_hash.get(hash).map(|entry| entry.status)
+    }
+
+    /// Drop the transactions received longer than the expiry time before `now`
+    pub fn expire(&mut self, now: Instant) {
+        while let Some(&(received, hash)) = self.by_arrival.iter().next() {
+            if now.saturating_duration_since(received) < self.config.expiry {
+                break;
+            }
+            self.stats.expired += self.remove_with_descendants(&hash).len() as u64;
+        }
+    }
+
+    pub fn contains(&self, hash: &H256) -> bool {
+        self.by_hash.contains_key(hash)
+    }
+
+    pub fn get(&self, hash: &H256) -> Option<&SignedTransaction> {
+        self.by_hash.get(hash).map(|entry| &entry.txn)
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_hash.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_hash.is_empty()
+    }
+
+    pub fn stats(&self) -> MempoolStats {
+        let ready = self.by_hash.values().filter(|entry| entry.status == TransactionStatus::Ready).count();
+        MempoolStats {
+            transactions: self.by_hash.len(),
+            ready,
+            future: self.by_hash.len() - ready,
+            bytes: self.bytes,
+            ..self.stats
+        }
+    }
+
+    /// All transactions, in no particular order
+    pub fn transactions(&self) -> impl Iterator<Item = &SignedTransaction> {
+        self.by_hash.values().map(|entry| &entry.txn)
+    }
+
+    /// Senders with at least one pending transaction
+    pub fn senders(&self) -> impl Iterator<Item = &Address> {
+        self.by_sender.keys()
+    }
+
+    /// Pending transactions of `sender`, in increasing account nonce order
+    pub fn sender_transactions(&self, sender: &Address) -> impl Iterator<Item = &SignedTransaction> {
+        self.by_sender.get(sender)
+            .into_iter()
+            .flat_map(|nonces| nonces.values())
+            .map(move |hash| &self.by_hash[hash].txn)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::{test_params, Blockchain};
+    use crate::blockchain::tests::{child_block, child_block_with, genesis_address, signed_transaction};
+    use crate::types::address::generate_random_address;
+
+    #[test]
+    fn sender_transactions_ordered_by_nonce() {
+        let mut mempool = Mempool::new();
+        let txn3 = signed_transaction(0, 3, generate_random_address(), 1, 1);
+        let txn1 = signed_transaction(0, 1, generate_random_address(), 1, 1);
+        let txn2 = signed_transaction(0, 2, generate_random_address(), 1, 1);
+        let other = signed_transaction(1, 1, generate_random_address(), 1, 1);
+        for txn in [&txn3, &txn1, &txn2, &other] {
+            assert_eq!(mempool.insert(txn.clone()).unwrap().replaced, None);
+        }
+        assert_eq!(mempool.len(), 4);
+        assert_eq!(mempool.senders().count(), 2);
+
+        let sender = Address::from_public_key_bytes(&txn1.public_key);
+        let nonces: Vec<u128> = mempool.sender_transactions(&sender)
+            .map(|txn| txn.transaction.account_nonce)
+            .collect();
+        assert_eq!(nonces, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn insert_rejects_duplicates() {
+        let mut mempool = Mempool::new();
+        let txn = signed_transaction(0, 1, generate_random_address(), 1, 1);
+        assert_eq!(mempool.insert(txn.clone()).unwrap().replaced, None);
+        assert_eq!(mempool.insert(txn.clone()), Err(MempoolError::Duplicate));
+
+        // Same sender and nonce, same fee
+        let conflict = signed_transaction(0, 1, generate_random_address(), 2, 1);
+        assert_eq!(mempool.insert(conflict.clone()), Err(MempoolError::ReplacementFeeTooLow { fee: 1, required: 2 }));
+        assert!(!mempool.contains(&conflict.hash()));
+
+        // Nonce is free again once the first transaction is removed
+        assert!(mempool.remove(&txn.hash()).is_some());
+        assert!(mempool.remove(&txn.hash()).is_none());
+        assert!(mempool.is_empty());
+        assert_eq!(mempool.senders().count(), 0);
+        assert_eq!(mempool.stats().bytes, 0);
+        assert_eq!(mempool.insert(conflict.clone()).unwrap().replaced, None);
+        assert_eq!(mempool.get(&conflict.hash()).unwrap().transaction.value, 2);
+    }
+
+    #[test]
+    fn replace_by_fee() {
+        let mut mempool = Mempool::new();
+        let original = signed_transaction(0, 1, generate_random_address(), 1, 100);
+        let child = signed_transaction(0, 2, generate_random_address(), 1, 1);
+        assert_eq!(mempool.insert(original.clone()).unwrap().replaced, None);
+        assert_eq!(mempool.insert(child.clone()).unwrap().replaced, None);
+
+        // A replacement must raise the fee by at least 10%
+        assert_eq!(mempool.replacement_fee(100), 110);
+        assert_eq!(mempool.replacement_fee(0), 1);
+        let cheap = signed_transaction(0, 1, generate_random_address(), 2, 109);
+        assert_eq!(mempool.insert(cheap.clone()), Err(MempoolError::ReplacementFeeTooLow { fee: 109, required: 110 }));
+        assert!(mempool.contains(&original.hash()));
+
+        // The replaced transaction is dropped while its sender's later nonces stay
+        let replacement = signed_transaction(0, 1, generate_random_address(), 2, 110);
+        assert_eq!(mempool.insert(replacement.clone()).unwrap().replaced, Some(original.hash()));
+        assert!(!mempool.contains(&original.hash()));
+        assert!(mempool.contains(&replacement.hash()));
+        assert!(mempool.contains(&child.hash()));
+        assert_eq!(mempool.len(), 2);
+        assert_eq!(mempool.stats().replaced, 1);
+
+        // The replaced transaction cannot come back
+        assert_eq!(mempool.insert(original), Err(MempoolError::ReplacementFeeTooLow { fee: 100, required: 121 }));
+    }
+
+    #[test]
+    fn reorg_returns_transactions() {
+        let mut blockchain = Blockchain::with_params(test_params());
+        let mut mempool = Mempool::new();
+        let genesis_hash = blockchain.tip();
+        let first = signed_transaction(0, 1, genesis_address(1), 100, 2);
+        let second = signed_transaction(0, 2, genesis_address(1), 100, 2);
+        assert_eq!(mempool.insert(first.clone()).unwrap().replaced, None);
+        assert_eq!(mempool.insert(second.clone()).unwrap().replaced, None);
+
+        // Transactions leave the mempool once their block joins the main chain
+        let block1 = child_block_with(&blockchain, &genesis_hash, 1_000_000, vec![first.clone()]);
+        let update = blockchain.insert(&block1).unwrap();
+        mempool.update_chain(&blockchain, &update);
+        assert!(!mempool.contains(&first.hash()));
+        assert!(mempool.contains(&second.hash()));
+
+        // and come back when a fork without them overtakes it
+        let block2 = child_block(&blockchain, &genesis_hash, 1_000_000);
+        assert!(blockchain.insert(&block2).unwrap().is_empty());
+        let block3 = child_block(&blockchain, &block2.hash(), 1_001_000);
+        let update = blockchain.insert(&block3).unwrap();
+        assert_eq!(update.disconnected, vec![block1.hash()]);
+        mempool.update_chain(&blockchain, &update);
+        assert!(mempool.contains(&first.hash()));
+        assert!(mempool.contains(&second.hash()));
+
+        // A different transaction using the same nonce on the main chain makes one stale
+        let conflict = signed_transaction(0, 1, genesis_address(2), 50, 1);
+        let block4 = child_block_with(&blockchain, &block3.hash(), 1_002_000, vec![conflict]);
+        let update = blockchain.insert(&block4).unwrap();
+        mempool.update_chain(&blockchain, &update);
+        assert!(!mempool.contains(&first.hash()));
+        assert!(mempool.contains(&second.hash()));
+    }
+
+    #[test]
+    fn revalidate_classifies_transactions() {
+        let blockchain = Blockchain::with_params(test_params());
+        let mut mempool = Mempool::new();
+        mempool.revalidate(blockchain.get_state(&blockchain.tip()).unwrap());
+
+        // Seed 0 holds 10000, seed 1 holds nothing
+        let first = signed_transaction(0, 1, genesis_address(1), 6000, 1);
+        let second = signed_transaction(0, 2, genesis_address(1), 4000, 1);
+        let after_gap = signed_transaction(0, 4, genesis_address(1), 1, 1);
+        let unfunded = signed_transaction(1, 1, genesis_address(0), 1, 1);
+        for txn in [&first, &second, &after_gap, &unfunded] {
+            assert_eq!(mempool.insert(txn.clone()).unwrap().replaced, None);
         }
+        assert_eq!(mempool.status(&first.hash()), Some(TransactionStatus::Ready));
+        assert_eq!(mempool.status(&second.hash()), Some(TransactionStatus::Future));
+        assert_eq!(mempool.status(&after_gap.hash()), Some(TransactionStatus::Future));
+        assert_eq!(mempool.status(&unfunded.hash()), Some(TransactionStatus::Future));
+
+        // A tip where the sender used nonce 1 and was paid makes the rest ready, except past the gap
+        let mut state = blockchain.get_state(&blockchain.tip()).unwrap().clone();
+        state.insert(genesis_address(0), (1, 5000));
+        state.insert(genesis_address(1), (0, 10));
+        mempool.revalidate(&state);
+        assert!(!mempool.contains(&first.hash()));
+        assert_eq!(mempool.status(&second.hash()), Some(TransactionStatus::Ready));
+        assert_eq!(mempool.status(&after_gap.hash()), Some(TransactionStatus::Future));
+        assert_eq!(mempool.status(&unfunded.hash()), Some(TransactionStatus::Ready));
+        let stats = mempool.stats();
+        assert_eq!((stats.ready, stats.future, stats.invalidated), (2, 1, 1));
+
+        // Used nonces and forged signatures are refused outright
+        assert_eq!(mempool.insert(first), Err(MempoolError::StaleNonce { account_nonce: 1, found: 1 }));
+        let mut forged = signed_transaction(0, 3, genesis_address(1), 1, 1);
+        forged.transaction.value = 2;
+        assert_eq!(mempool.insert(forged), Err(MempoolError::InvalidSignature));
+    }
+
+    #[test]
+    fn validate_follows_ready_transactions() {
+        let blockchain = Blockchain::with_params(test_params());
+        let mut mempool = Mempool::new();
+        mempool.revalidate(blockchain.get_state(&blockchain.tip()).unwrap());
+
+        // Seed 0 holds 10000 and has one ready transaction pending
+        let first = signed_transaction(0, 1, genesis_address(1), 6000, 1);
+        assert!(mempool.validate(&first).is_ok());
+        assert_eq!(mempool.insert(first).unwrap().replaced, None);
+
+        assert!(mempool.validate(&signed_transaction(0, 2, genesis_address(1), 3999, 0)).is_ok());
+        assert_eq!(mempool.validate(&signed_transaction(0, 2, genesis_address(1), 4000, 0)),
+                   Err(TransactionError::InsufficientBalance { balance: 3999, required: 4000 }));
+        assert_eq!(mempool.validate(&signed_transaction(0, 3, genesis_address(1), 1, 1)),
+                   Err(TransactionError::InvalidNonce { expected: 2, found: 3 }));
+
+        // A replacement is checked against the state before the transaction it replaces
+        assert!(mempool.validate(&signed_transaction(0, 1, genesis_address(1), 9000, 2)).is_ok());
+
+        let unknown = signed_transaction(7, 1, generate_random_address(), 1, 1);
+        assert_eq!(mempool.validate(&unknown), Err(TransactionError::UnknownSender));
+
+        // A tip state the ready transactions were not classified against cannot underflow
+        mempool.tip_state.insert(genesis_address(0), (0, 5000));
+        assert_eq!(mempool.validate(&signed_transaction(0, 2, genesis_address(1), 1, 0)),
+                   Err(TransactionError::InsufficientBalance { balance: 5000, required: 6001 }));
+    }
+
+    #[test]
+    fn insert_enforces_min_relay_fee() {
+        let mut mempool = Mempool::with_config(MempoolConfig { min_relay_fee: 5, ..Default::default() });
+        let txn = signed_transaction(0, 1, generate_random_address(), 1, 4);
+        assert_eq!(mempool.insert(txn), Err(MempoolError::FeeTooLow { fee: 4, min: 5 }));
+        assert_eq!(mempool.insert(signed_transaction(0, 1, generate_random_address(), 1, 5)).unwrap().replaced, None);
+        assert_eq!(mempool.stats().rejected_low_fee, 1);
+    }
+
+    #[test]
+    fn full_mempool_evicts_lowest_fee_rate() {
+        let mut mempool = Mempool::with_config(MempoolConfig { max_transactions: 3, ..Default::default() });
+        let low = signed_transaction(0, 1, generate_random_address(), 1, 2);
+        let low_child = signed_transaction(0, 2, generate_random_address(), 1, 50);
+        let medium = signed_transaction(1, 1, generate_random_address(), 1, 10);
+        for txn in [&low, &low_child, &medium] {
+            assert_eq!(mempool.insert(txn.clone()).unwrap().replaced, None);
+        }
+
+        // Paying less than everything in a full mempool is refused
+        let lowest = signed_transaction(2, 1, generate_random_address(), 1, 1);
+        assert_eq!(mempool.insert(lowest), Err(MempoolError::Full));
+        assert_eq!(mempool.len(), 3);
+
+        // Evicting the lowest fee rate transaction takes its sender's later nonce with it
+        let high = signed_transaction(2, 1, generate_random_address(), 1, 20);
+        assert_eq!(mempool.insert(high.clone()).unwrap().replaced, None);
+        assert!(!mempool.contains(&low.hash()));
+        assert!(!mempool.contains(&low_child.hash()));
+        assert!(mempool.contains(&medium.hash()));
+        assert!(mempool.contains(&high.hash()));
+
+        // Among equal fee rates the oldest goes first
+        let newer = signed_transaction(3, 1, generate_random_address(), 1, 10);
+        assert_eq!(mempool.insert(newer.clone()).unwrap().replaced, None);
+        let newest = signed_transaction(0, 1, generate_random_address(), 1, 10);
+        assert_eq!(mempool.insert(newest.clone()).unwrap().replaced, None);
+        assert!(!mempool.contains(&medium.hash()));
+        assert!(mempool.contains(&newer.hash()));
+
+        let stats = mempool.stats();
+        assert_eq!((stats.transactions, stats.evicted, stats.rejected_full), (3, 3, 1));
+        assert_eq!(mempool.by_eviction.len(), mempool.len());
+    }
+
+    #[test]
+    fn byte_limit_evicts() {
+        let txn = signed_transaction(0, 1, generate_random_address(), 1, 1);
+        let size = bincode::serialized_size(&txn).unwrap() as usize;
+        let mut mempool = Mempool::with_config(MempoolConfig { max_bytes: 2 * size, ..Default::default() });
+        assert_eq!(mempool.insert(txn.clone()).unwrap().replaced, None);
+        assert_eq!(mempool.insert(signed_transaction(1, 1, generate_random_address(), 1, 2)).unwrap().replaced, None);
+        assert_eq!(mempool.insert(signed_transaction(2, 1, generate_random_address(), 1, 3)).unwrap().replaced, None);
+        assert!(!mempool.contains(&txn.hash()));
+        assert_eq!(mempool.stats().bytes, 2 * size);
+    }
+
+    #[test]
+    fn transactions_expire() {
+        let mut mempool = Mempool::new();
+        let parent = signed_transaction(0, 1, generate_random_address(), 1, 1);
+        let child = signed_transaction(0, 2, generate_random_address(), 1, 1);
+        let other = signed_transaction(1, 1, generate_random_address(), 1, 1);
+        assert_eq!(mempool.insert(parent.clone()).unwrap().replaced, None);
+        std::thread::sleep(Duration::from_millis(10));
+        assert_eq!(mempool.insert(other.clone()).unwrap().replaced, None);
+        assert_eq!(mempool.insert(child.clone()).unwrap().replaced, None);
+
+        // The parent expires first and takes the child with it
+        let now = Instant::now() + mempool.config().expiry - Duration::from_millis(5);
+        mempool.expire(now);
+        assert_eq!(mempool.len(), 1);
+        assert!(mempool.contains(&other.hash()));
+        assert_eq!(mempool.stats().expired, 2);
+    }
+
+    #[test]
+    fn insert_reports_template_improvements() {
+        let mut mempool = Mempool::new();
+        let mut state = State::new();
+        for seed in 0..4 {
+            state.insert(genesis_address(seed), (0, 1000));
+        }
+        mempool.revalidate(&state);
+
+        // While the template has room, every ready transaction improves it
+        let first = signed_transaction(0, 1, generate_random_address(), 10, 5);
+        assert!(mempool.insert(first.clone()).unwrap().improves_template);
+
+        // A transaction waiting for a missing nonce does not
+        assert!(!mempool.insert(signed_transaction(1, 2, generate_random_address(), 10, 50)).unwrap().improves_template);
+
+        // Once the template is full, only a better fee rate than its floor does
+        let size = bincode::serialized_size(&first).unwrap() as usize;
+        mempool.set_template_floor(Some((5, size)));
+        assert!(!mempool.insert(signed_transaction(2, 1, generate_random_address(), 10, 5)).unwrap().improves_template);
+        assert!(mempool.insert(signed_transaction(3, 1, generate_random_address(), 10, 6)).unwrap().improves_template);
+    }
+
+    #[test]
+    fn replacement_evicted_for_room_keeps_original() {
+        let mut mempool = Mempool::with_config(MempoolConfig { max_transactions: 3, ..Default::default() });
+        let low = signed_transaction(0, 1, generate_random_address(), 1, 10);
+        let low_child = signed_transaction(0, 2, generate_random_address(), 1, 50);
+        let high = signed_transaction(1, 1, generate_random_address(), 1, 50);
+        for txn in [&low, &low_child, &high] {
+            assert_eq!(mempool.insert(txn.clone()).unwrap().replaced, None);
+        }
+        mempool.config.max_transactions = 2;    // the pool is now over its limit
+
+        // A replacement paying the bump but still the lowest fee rate would be the one evicted,
+        // so the original and the nonce after it stay
+        let bumped = signed_transaction(0, 1, generate_random_address(), 2, 11);
+        assert_eq!(mempool.insert(bumped.clone()), Err(MempoolError::Full));
+        assert!(!mempool.contains(&bumped.hash()));
+        assert!(mempool.contains(&low.hash()));
+        assert!(mempool.contains(&low_child.hash()));
+        assert_eq!((mempool.stats().replaced, mempool.stats().rejected_full), (0, 1));
+
+        // One paying enough replaces the original and evicts the oldest of the lowest rate
+        let better = signed_transaction(0, 1, generate_random_address(), 3, 60);
+        assert_eq!(mempool.insert(better.clone()).unwrap().replaced, Some(low.hash()));
+        assert!(!mempool.contains(&low_child.hash()));
+        assert!(mempool.contains(&high.hash()));
+        let stats = mempool.stats();
+        assert_eq!((stats.transactions, stats.replaced, stats.evicted), (2, 1, 1));
+        assert_eq!(mempool.by_eviction.len(), mempool.len());
     }
 }
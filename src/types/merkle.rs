@@ -1,5 +1,7 @@
 use super::hash::{Hashable, H256};
+use super::block::Header;
 use ring::digest::{Context, SHA256};
+use serde::{Serialize, Deserialize};
 use hex_literal::hex;
 
 /// A Merkle tree.
@@ -10,9 +12,25 @@ pub struct MerkleTree {
     leaf_count: usize
 }
 
+/// The Merkle path of a transaction in a block, with the header holding the merkle root.
+/// Leaf 0 of a block is its coinbase, so transactions start at index 1.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TransactionProof {
+    pub header: Header,
+    pub index: usize,
+    pub leaf_count: usize,
+    pub proof: Vec<H256>
+}
+
 impl MerkleTree {
     /// Creates a new Merkle tree, given a slice of Hashable data as input. 
     pub fn new<T>(data: &[T]) -> Self where T: Hashable, {
+        let leaves: Vec<H256> = data.iter().map(|item| item.hash()).collect();
+        Self::from_leaves(&leaves)
+    }
+
+    /// Creates a new Merkle tree whose leaves are the given hashes.
+    pub fn from_leaves(data: &[H256]) -> Self {
         if data.is_empty() {
             // handle empty input case
             let item: H256 = (hex!("0000000000000000000000000000000000000000000000000000000000000000")).into();
@@ -33,13 +51,13 @@ impl MerkleTree {
 
         // Fill in the leaf nodes with hashed data
         for (i, item) in data.iter().enumerate() {
-            nodes[first_leaf_index + i] = Some(item.hash());
+            nodes[first_leaf_index + i] = Some(*item);
         }
 
         // Add duplicate node to leaf row if it has odd number of elements
         if leaf_count % 2 == 1 && max_level > 0 {
             nodes[first_leaf_index + leaf_count] = nodes[first_leaf_index + leaf_count - 1];
-            leaf_count = leaf_count + 1;
+            leaf_count += 1;
         }
     
         let mut level_count = leaf_count / 2;
@@ -49,13 +67,13 @@ impl MerkleTree {
 
             for i in 0..level_count {
                 let current_index = level_first_index + i;
-                let left = nodes[2 * current_index + 1].clone().unwrap_or_default();
-                let right = nodes[2 * current_index + 2].clone().unwrap_or_default();
+                let left = nodes[2 * current_index + 1].unwrap_or_default();
+                let right = nodes[2 * current_index + 2].unwrap_or_default();
 
                 // Use left and right hashes to create a combined hash
                 let mut context = Context::new(&SHA256);
-                context.update(&left.as_ref());
-                context.update(&right.as_ref());
+                context.update(left.as_ref());
+                context.update(right.as_ref());
                 let combined_hash = context.finish();
                 
                 nodes[current_index] = Some(combined_hash.into());
@@ -68,13 +86,13 @@ impl MerkleTree {
             }
 
             // update max_level count
-            level_count = level_count / 2;
+            level_count /= 2;
         }
 
         MerkleTree {
-            root: nodes[0].clone(),
-            nodes: nodes,
-            leaf_count: leaf_count,
+            root: nodes[0],
+            nodes,
+            leaf_count,
         }
     }
 
@@ -96,7 +114,7 @@ impl MerkleTree {
 
         // Start from the leaf level and go upwards through tree (excluding root)
         for _level in (1..(max_level + 1)).rev() {
-            if current_index % 2 == 0 {
+            if current_index.is_multiple_of(2) {
                 // If the current node is a right child, add the sibling on the left
                 let sibling_index = current_index - 1;
                 let sibling_hash = &self.nodes[sibling_index];
@@ -125,25 +143,25 @@ pub fn verify(root: &H256, datum: &H256, proof: &[H256], index: usize, leaf_size
     }
 
     let mut current_index = leaf_size.next_power_of_two() - 1 + index;
-    let mut current_hash = datum.clone();
+    let mut current_hash = *datum;
 
     for sibling_hash in proof.iter() {
         let mut context = Context::new(&SHA256);
 
         // Check if current node is left or right child, 
         // in order to preserve the original order in the combined hashing
-        if current_index % 2 == 0 {   
+        if current_index.is_multiple_of(2) {   
             // current node is a right child, so hash sibling & current
-            context.update(&sibling_hash.as_ref());
-            context.update(&current_hash.as_ref());
+            context.update(sibling_hash.as_ref());
+            context.update(current_hash.as_ref());
 
             // move current_index up to parent
             current_index = (current_index - 2) / 2;
         }
         else {                          
             // current node is a left child, so hash current & sibling
-            context.update(&current_hash.as_ref());
-            context.update(&sibling_hash.as_ref());
+            context.update(current_hash.as_ref());
+            context.update(sibling_hash.as_ref());
             
             // move current_index up to parent
             current_index = (current_index - 1) / 2;
@@ -543,7 +561,7 @@ mod tests {
         let item: H256 = (hex!("0000000000000000000000000000000000000000000000000000000000000000")).into();
 
         assert_eq!(proof.len(), 0);
-        assert_eq!(verify(&merkle_tree.root(), &item, &proof, 0, input_data.len()), false);
+        assert!(!verify(&merkle_tree.root(), &item, &proof, 0, input_data.len()));
     }   
 }
 
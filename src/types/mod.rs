@@ -5,4 +5,6 @@ pub mod merkle;
 pub mod key_pair;
 pub mod transaction;
 pub mod mempool;
-pub mod state;
\ No newline at end of file
+pub mod state;
+pub mod sparse_merkle;
+pub mod uint;
@@ -0,0 +1,396 @@
+use super::address::Address;
+use super::block::Header;
+use super::hash::H256;
+use super::state::State;
+use ring::digest::{Context, SHA256};
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+use std::sync::{Arc, OnceLock};
+
+// Number of levels below the root, one for every bit of an account key
+const DEPTH: usize = 256;
+
+/// A sparse Merkle tree over the accounts of a State. Every account sits at the leaf reached
+/// by the bits of the hash of its address and every other leaf is empty, so a single path
+/// proves either the account's (account nonce, balance) or that the account does not exist.
+///
+/// Only the subtrees holding accounts are stored, in nodes shared by the trees of consecutive
+/// blocks, so the tree of a block is derived from its parent's by hashing the paths of the
+/// accounts the block changed.
+#[derive(Debug, Clone)]
+pub struct SparseMerkleTree {
+    node: Option<Arc<Node>>,    // top node, or None if there are no accounts
+    root: H256
+}
+
+// A stored subtree. A path on which a single account is left is not stored, so a node only
+// records the depth where its subtree stops being a single path.
+#[derive(Debug)]
+enum Node {
+    // A subtree holding one account
+    Leaf { key: H256, hash: H256 },
+    // A subtree whose accounts share the bits of `key` above `split` and differ at `split`;
+    // `hash` is the root of the subtree at `split`
+    Branch { key: H256, split: usize, left: Arc<Node>, right: Arc<Node>, hash: H256 },
+}
+
+/// The siblings on the path from an account's leaf to the root. Siblings that are empty
+/// subtrees are left out and only marked in `bitmap`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct StateProof {
+    bitmap: [u8; 32],       // bit i is set if the sibling at depth i is stored
+    siblings: Vec<H256>     // stored siblings, from the root down
+}
+
+/// An account's (account nonce, balance), or its absence, at some block, proven against the
+/// state root in that block's header
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AccountProof {
+    pub header: Header,
+    pub address: Address,
+    pub account: Option<(u128, u128)>,
+    pub proof: StateProof
+}
+
+impl SparseMerkleTree {
+    /// Creates the tree committing to every account of the given state.
+    pub fn new(state: &State) -> Self {
+        let empty = SparseMerkleTree { node: None, root: empty_root(0) };
+        empty.update(&state.accounts())
+    }
+
+    /// Returns the tree with the given accounts set, sharing every subtree they leave
+    /// untouched with this one. Updating the tree of a block's parent with the block's
+    /// `State::changes` gives the tree of the block.
+    pub fn update(&self, changes: &HashMap<Address, (u128, u128)>) -> Self {
+        let mut node = self.node.clone();
+        for (address, info) in changes.iter() {
+            let key = account_key(address);
+            node = Some(insert(node.as_ref(), 0, key, leaf_hash(&key, *info)));
+        }
+        let root = node.as_ref().map_or_else(|| empty_root(0), |node| node.hash_at(0));
+        SparseMerkleTree { node, root }
+    }
+
+    /// Returns the root of the tree, the state root committed by block headers.
+    pub fn root(&self) -> H256 {
+        self.root
+    }
+
+    /// Returns the proof of the account at `address`, or of its absence.
+    pub fn prove(&self, address: &Address) -> StateProof {
+        let key = account_key(address);
+        let mut proof = StateProof::default();
+        let mut node = self.node.as_deref();
+
+        for depth in 0..DEPTH {
+            // Follow the path of the key and keep the hash of the other side, if not empty
+            let sibling_hash = match node {
+                Some(Node::Branch { split, left, right, .. }) if *split == depth => {
+                    let (path, sibling) = if bit(&key, depth) { (right, left) } else { (left, right) };
+                    node = Some(&**path);
+                    Some(sibling.hash_at(depth + 1))
+                }
+                Some(other) if bit(other.key(), depth) != bit(&key, depth) => {
+                    // The key leaves the only path stored here, which is all on the other side
+                    node = None;
+                    Some(other.hash_at(depth + 1))
+                }
+                _ => None,
+            };
+            if let Some(sibling_hash) = sibling_hash {
+                proof.bitmap[depth / 8] |= 0x80 >> (depth % 8);
+                proof.siblings.push(sibling_hash);
+            }
+        }
+
+        proof
+    }
+}
+
+/// Verify that `proof` shows the account at `address` to hold `account` under the state
+/// `root`, where an `account` of None proves that the address has no account.
+pub fn verify(root: &H256, address: &Address, account: Option<(u128, u128)>, proof: &StateProof) -> bool {
+    let key = account_key(address);
+    let mut current_hash = match account {
+        Some(info) => leaf_hash(&key, info),
+        None => empty_root(DEPTH),
+    };
+
+    // Walk from the leaf up to the root, taking stored siblings from the end of the proof
+    let mut siblings = proof.siblings.iter().rev();
+    for depth in (0..DEPTH).rev() {
+        let sibling_hash = if proof.bitmap[depth / 8] & (0x80 >> (depth % 8)) != 0 {
+            match siblings.next() {
+                Some(hash) => *hash,
+                None => return false,    // bitmap marks more siblings than the proof holds
+            }
+        } else {
+            empty_root(depth + 1)
+        };
+
+        current_hash = if bit(&key, depth) {
+            node_hash(&sibling_hash, &current_hash)
+        } else {
+            node_hash(&current_hash, &sibling_hash)
+        };
+    }
+
+    siblings.next().is_none() && current_hash == *root
+}
+
+/// Verify that an account proof matches the state root of its header. The caller still has
+/// to check that the header, whose hash identifies the block, is in the chain it follows.
+pub fn verify_account(proof: &AccountProof) -> bool {
+    verify(&proof.header.state_root, &proof.address, proof.account, &proof.proof)
+}
+
+// Position of an account in the tree
+fn account_key(address: &Address) -> H256 {
+    ring::digest::digest(&SHA256, address.as_ref()).into()
+}
+
+// Bit of `key` at `depth`, counting from the most significant bit; set means right child
+fn bit(key: &H256, depth: usize) -> bool {
+    key.as_ref()[depth / 8] & (0x80 >> (depth % 8)) != 0
+}
+
+// Leaves and inner nodes are hashed with different prefixes so that one cannot pass for the other
+fn leaf_hash(key: &H256, (account_nonce, balance): (u128, u128)) -> H256 {
+    let mut context = Context::new(&SHA256);
+    context.update(&[0]);
+    context.update(key.as_ref());
+    context.update(&account_nonce.to_be_bytes());
+    context.update(&balance.to_be_bytes());
+    context.finish().into()
+}
+
+fn node_hash(left: &H256, right: &H256) -> H256 {
+    let mut context = Context::new(&SHA256);
+    context.update(&[1]);
+    context.update(left.as_ref());
+    context.update(right.as_ref());
+    context.finish().into()
+}
+
+// Root of an empty subtree whose root is at `depth`; an empty leaf is the zero hash
+fn empty_root(depth: usize) -> H256 {
+    static EMPTY_ROOTS: OnceLock<Vec<H256>> = OnceLock::new();
+    let roots = EMPTY_ROOTS.get_or_init(|| {
+        let mut roots = vec![H256::default(); DEPTH + 1];
+        for depth in (0..DEPTH).rev() {
+            roots[depth] = node_hash(&roots[depth + 1], &roots[depth + 1]);
+        }
+        roots
+    });
+    roots[depth]
+}
+
+impl Node {
+    // A key of an account in the subtree, whose bits above `depth()` are the subtree's path
+    fn key(&self) -> &H256 {
+        match self {
+            Node::Leaf { key, .. } | Node::Branch { key, .. } => key,
+        }
+    }
+
+    // Depth of the first node of the subtree with two non-empty children, or of its leaf
+    fn depth(&self) -> usize {
+        match self {
+            Node::Leaf { .. } => DEPTH,
+            Node::Branch { split, .. } => *split,
+        }
+    }
+
+    // Root of the subtree at `depth`, above `depth()` on this node's path, that holds only
+    // this node's accounts
+    fn hash_at(&self, depth: usize) -> H256 {
+        let key = self.key();
+        let mut hash = match self {
+            Node::Leaf { hash, .. } | Node::Branch { hash, .. } => *hash,
+        };
+        for level in (depth..self.depth()).rev() {
+            hash = if bit(key, level) {
+                node_hash(&empty_root(level + 1), &hash)
+            } else {
+                node_hash(&hash, &empty_root(level + 1))
+            };
+        }
+        hash
+    }
+}
+
+// A branch at `split` over two nodes whose keys go left and right there
+fn branch(split: usize, left: Arc<Node>, right: Arc<Node>) -> Arc<Node> {
+    let hash = node_hash(&left.hash_at(split + 1), &right.hash_at(split + 1));
+    Arc::new(Node::Branch { key: *left.key(), split, left, right, hash })
+}
+
+// Set the leaf at `key` in the subtree at `depth` held by `node`, returning the new subtree.
+// Only the nodes on the key's path are replaced; the others are shared.
+fn insert(node: Option<&Arc<Node>>, depth: usize, key: H256, hash: H256) -> Arc<Node> {
+    let node = match node {
+        Some(node) => node,
+        None => return Arc::new(Node::Leaf { key, hash }),
+    };
+
+    // The key leaves the path of the node above it, so both hang from a new branch there
+    if let Some(split) = (depth..node.depth()).find(|level| bit(&key, *level) != bit(node.key(), *level)) {
+        let leaf = Arc::new(Node::Leaf { key, hash });
+        return if bit(&key, split) {
+            branch(split, Arc::clone(node), leaf)
+        } else {
+            branch(split, leaf, Arc::clone(node))
+        };
+    }
+
+    match &**node {
+        Node::Leaf { .. } => Arc::new(Node::Leaf { key, hash }),
+        Node::Branch { split, left, right, .. } => {
+            if bit(&key, *split) {
+                branch(*split, Arc::clone(left), insert(Some(right), split + 1, key, hash))
+            } else {
+                branch(*split, insert(Some(left), split + 1, key, hash), Arc::clone(right))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::address::generate_random_address;
+
+    // Root of the subtree at `depth` holding the given <key, leaf hash> pairs, sorted by key,
+    // computed level by level as the definition of the state root
+    fn reference_root(leaves: &[(H256, H256)], depth: usize) -> H256 {
+        if leaves.is_empty() {
+            return empty_root(depth);
+        }
+        if depth == DEPTH {
+            return leaves[0].1;
+        }
+        let split = leaves.partition_point(|(key, _)| !bit(key, depth));
+        node_hash(&reference_root(&leaves[..split], depth + 1), &reference_root(&leaves[split..], depth + 1))
+    }
+
+    fn random_state(accounts: usize) -> (State, Vec<Address>) {
+        let mut state = State::new();
+        let addresses: Vec<Address> = (0..accounts).map(|_| generate_random_address()).collect();
+        for (i, address) in addresses.iter().enumerate() {
+            state.insert(*address, (i as u128, 100 * i as u128));
+        }
+        (state, addresses)
+    }
+
+    #[test]
+    fn root_commits_to_accounts() {
+        assert_eq!(SparseMerkleTree::new(&State::new()).root(), empty_root(0));
+
+        let (mut state, addresses) = random_state(5);
+        let root = SparseMerkleTree::new(&state).root();
+        assert_ne!(root, empty_root(0));
+
+        // Insertion order does not matter, but every account value does
+        let mut reversed = State::new();
+        for address in addresses.iter().rev() {
+            reversed.insert(*address, state.get(address).unwrap());
+        }
+        assert_eq!(SparseMerkleTree::new(&reversed).root(), root);
+
+        state.insert(addresses[2], (2, 201));
+        assert_ne!(SparseMerkleTree::new(&state).root(), root);
+    }
+
+    #[test]
+    fn inclusion_proof() {
+        let (state, addresses) = random_state(20);
+        let tree = SparseMerkleTree::new(&state);
+
+        for address in addresses.iter() {
+            let account = state.get(address);
+            let proof = tree.prove(address);
+            assert!(verify(&tree.root(), address, account, &proof));
+
+            // The proof is bound to the account value, the address and the root
+            let (nonce, balance) = account.unwrap();
+            assert!(!verify(&tree.root(), address, Some((nonce, balance + 1)), &proof));
+            assert!(!verify(&tree.root(), address, None, &proof));
+            assert!(!verify(&tree.root(), &generate_random_address(), account, &proof));
+            assert!(!verify(&empty_root(0), address, account, &proof));
+        }
+    }
+
+    #[test]
+    fn non_inclusion_proof() {
+        let (state, addresses) = random_state(20);
+        let tree = SparseMerkleTree::new(&state);
+
+        let missing = generate_random_address();
+        let proof = tree.prove(&missing);
+        assert!(verify(&tree.root(), &missing, None, &proof));
+        assert!(!verify(&tree.root(), &missing, Some((0, 0)), &proof));
+
+        // An existing account cannot be proven absent
+        let proof = tree.prove(&addresses[0]);
+        assert!(!verify(&tree.root(), &addresses[0], None, &proof));
+
+        // Every address is absent from the empty tree
+        let empty = SparseMerkleTree::new(&State::new());
+        let proof = empty.prove(&missing);
+        assert!(proof.siblings.is_empty());
+        assert!(verify(&empty.root(), &missing, None, &proof));
+    }
+
+    #[test]
+    fn truncated_proof_is_rejected() {
+        let (state, addresses) = random_state(8);
+        let tree = SparseMerkleTree::new(&state);
+        let mut proof = tree.prove(&addresses[3]);
+        proof.siblings.pop();
+        assert!(!verify(&tree.root(), &addresses[3], state.get(&addresses[3]), &proof));
+    }
+
+    #[test]
+    fn update_matches_rebuild() {
+        let (state, addresses) = random_state(50);
+        let tree = SparseMerkleTree::new(&state);
+        let mut leaves: Vec<(H256, H256)> = state.accounts().into_iter()
+            .map(|(address, info)| (account_key(&address), leaf_hash(&account_key(&address), info)))
+            .collect();
+        leaves.sort();
+        assert_eq!(tree.root(), reference_root(&leaves, 0));
+
+        // A block changing some accounts and creating others
+        let mut child = State::child(&state);
+        child.insert(addresses[3], (4, 0));
+        child.insert(addresses[17], (18, 5));
+        let created = generate_random_address();
+        child.insert(created, (0, 7));
+        let updated = tree.update(child.changes());
+        assert_eq!(updated.root(), SparseMerkleTree::new(&child).root());
+        assert_ne!(updated.root(), tree.root());
+
+        // Both trees keep proving their own state
+        for address in [addresses[3], addresses[10], created] {
+            assert!(verify(&updated.root(), &address, child.get(&address), &updated.prove(&address)));
+            assert!(verify(&tree.root(), &address, state.get(&address), &tree.prove(&address)));
+        }
+
+        // Setting an account back to its value gives back the root
+        let mut grandchild = State::child(&child);
+        grandchild.insert(addresses[3], state.get(&addresses[3]).unwrap());
+        grandchild.insert(addresses[17], state.get(&addresses[17]).unwrap());
+        let mut restored = updated.update(grandchild.changes());
+        assert_ne!(restored.root(), tree.root());    // the created account remains
+        let mut without_created = State::new();
+        for (address, info) in grandchild.accounts() {
+            if address != created {
+                without_created.insert(address, info);
+            }
+        }
+        assert_eq!(SparseMerkleTree::new(&without_created).root(), tree.root());
+        restored = restored.update(&HashMap::new());
+        assert_eq!(restored.root(), SparseMerkleTree::new(&grandchild).root());
+    }
+}
@@ -1,15 +1,192 @@
 use std::collections::HashMap;
+use std::sync::Arc;
 use crate::types::address::Address;
 
+// Number of children of a trie node, one per 4-bit digit of an address
+const RADIX: usize = 16;
+
+// A node of the account trie: a branch on one digit of the address, or a single account
 #[derive(Debug, Clone)]
+enum Node {
+    Branch([Option<Arc<Node>>; RADIX]),
+    Leaf(Address, (u128, u128)),
+}
+
+// A State is the account map at some block. Accounts are kept in a persistent trie over the
+// digits of their address, so a state derived from another copies only the nodes on the paths
+// to the accounts it changes and shares every other node with it.
+#[derive(Debug, Default, Clone)]
 pub struct State {
-    pub map: HashMap<Address, (u128, u128)>      // <account address, (account nonce, balance)>
+    root: Option<Arc<Node>>,
+    changes: HashMap<Address, (u128, u128)>     // <account address, (account nonce, balance)> set since derived
 }
 
 impl State {
     pub fn new() -> Self {
         Self {
-            map: HashMap::new()
+            root: None,
+            changes: HashMap::new()
+        }
+    }
+
+    /// A state on top of `parent`, to which the changes made by a block are applied.
+    /// It shares every account with its parent until the account is changed.
+    pub fn child(parent: &State) -> Self {
+        Self {
+            root: parent.root.clone(),
+            changes: HashMap::new()
+        }
+    }
+
+    /// Get the (account nonce, balance) of an account, if it exists
+    pub fn get(&self, address: &Address) -> Option<(u128, u128)> {
+        let mut node = self.root.as_deref()?;
+        let mut depth = 0;
+        loop {
+            match node {
+                Node::Branch(children) => node = children[digit(address, depth)].as_deref()?,
+                Node::Leaf(leaf_address, info) => return Some(*info).filter(|_| leaf_address == address),
+            }
+            depth += 1;
+        }
+    }
+
+    pub fn contains(&self, address: &Address) -> bool {
+        self.get(address).is_some()
+    }
+
+    /// Set the (account nonce, balance) of an account, creating it if needed
+    pub fn insert(&mut self, address: Address, info: (u128, u128)) {
+        insert(&mut self.root, 0, address, info);
+        self.changes.insert(address, info);
+    }
+
+    /// All accounts
+    pub fn accounts(&self) -> HashMap<Address, (u128, u128)> {
+        let mut accounts = HashMap::new();
+        let mut nodes: Vec<&Node> = self.root.as_deref().into_iter().collect();
+        while let Some(node) = nodes.pop() {
+            match node {
+                Node::Branch(children) => nodes.extend(children.iter().flatten().map(|child| &**child)),
+                Node::Leaf(address, info) => {
+                    accounts.insert(*address, *info);
+                }
+            }
+        }
+        accounts
+    }
+
+    /// Accounts changed in this state relative to the one it was derived from
+    pub fn changes(&self) -> &HashMap<Address, (u128, u128)> {
+        &self.changes
+    }
+}
+
+// Digit of `address` at `depth`, starting from the high half of its first byte
+fn digit(address: &Address, depth: usize) -> usize {
+    let byte = address.as_ref()[depth / 2];
+    (if depth.is_multiple_of(2) { byte >> 4 } else { byte & 0x0f }) as usize
+}
+
+// Set an account in the subtree at `depth` held by `slot`, copying the nodes on its path
+// that are shared with other states
+fn insert(slot: &mut Option<Arc<Node>>, depth: usize, address: Address, info: (u128, u128)) {
+    let node = match slot {
+        Some(node) => node,
+        None => {
+            *slot = Some(Arc::new(Node::Leaf(address, info)));
+            return;
+        }
+    };
+
+    if let Node::Leaf(leaf_address, _) = **node {
+        if leaf_address == address {
+            *node = Arc::new(Node::Leaf(address, info));
+            return;
+        }
+
+        // Another account sits here, so move it one level down under a new branch
+        let mut children: [Option<Arc<Node>>; RADIX] = Default::default();
+        children[digit(&leaf_address, depth)] = Some(Arc::clone(node));
+        *node = Arc::new(Node::Branch(children));
+    }
+
+    match Arc::make_mut(node) {
+        Node::Branch(children) => insert(&mut children[digit(&address, depth)], depth + 1, address, info),
+        Node::Leaf(..) => unreachable!(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::address::generate_random_address;
+
+    #[test]
+    fn child_shares_parent_accounts() {
+        let alice = generate_random_address();
+        let bob = generate_random_address();
+        let mut genesis = State::new();
+        genesis.insert(alice, (0, 100));
+        genesis.insert(bob, (0, 0));
+        let genesis = Arc::new(genesis);
+
+        let mut child = State::child(&genesis);
+        child.insert(alice, (1, 60));
+        child.insert(bob, (0, 40));
+        assert_eq!(child.changes().len(), 2);
+        assert_eq!(child.get(&alice), Some((1, 60)));
+        let child = Arc::new(child);
+
+        // Parent is unaffected by its child
+        assert_eq!(genesis.get(&alice), Some((0, 100)));
+        assert_eq!(genesis.get(&bob), Some((0, 0)));
+
+        let grandchild = State::child(&child);
+        assert!(grandchild.changes().is_empty());
+        assert_eq!(grandchild.get(&bob), Some((0, 40)));
+        assert!(!grandchild.contains(&generate_random_address()));
+        assert_eq!(grandchild.accounts(), child.accounts());
+    }
+
+    // The node holding the account at `address`
+    fn leaf<'a>(state: &'a State, address: &Address) -> &'a Node {
+        let mut node = state.root.as_deref().unwrap();
+        let mut depth = 0;
+        while let Node::Branch(children) = node {
+            node = children[digit(address, depth)].as_deref().unwrap();
+            depth += 1;
+        }
+        node
+    }
+
+    #[test]
+    fn deep_chains_share_untouched_accounts() {
+        let alice = generate_random_address();
+        let others: Vec<Address> = (0..200).map(|_| generate_random_address()).collect();
+        let mut genesis = State::new();
+        genesis.insert(alice, (0, 0));
+        for address in others.iter() {
+            genesis.insert(*address, (0, 1));
+        }
+        let genesis = Arc::new(genesis);
+
+        let mut state = Arc::clone(&genesis);
+        for i in 1..=100 {
+            let mut child = State::child(&state);
+            child.insert(alice, (i, i));
+            assert_eq!(child.changes().len(), 1);
+            state = Arc::new(child);
+        }
+        assert_eq!(state.get(&alice), Some((100, 100)));
+        assert_eq!(genesis.get(&alice), Some((0, 0)));
+        assert_eq!(state.accounts().len(), 201);
+
+        // Accounts no block touched are the very nodes of the genesis state
+        for address in others.iter() {
+            assert_eq!(state.get(address), Some((0, 1)));
+            assert!(std::ptr::eq(leaf(&state, address), leaf(&genesis, address)));
         }
+        assert!(!std::ptr::eq(leaf(&state, &alice), leaf(&genesis, &alice)));
     }
 }
@@ -1,7 +1,6 @@
 use super::hash::{Hashable, H256};
 use serde::{Serialize,Deserialize};
 use ring::signature::{Ed25519KeyPair, Signature};
-use rand::Rng;
 use ring::signature;
 
 use super::address::Address;
@@ -10,7 +9,8 @@ use super::address::Address;
 pub struct Transaction {
     pub account_nonce: u128,
     pub receiver: Address,
-    pub value: u128
+    pub value: u128,
+    pub fee: u128    // paid to the miner of the block including the transaction
 }
 
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
@@ -20,6 +20,23 @@ pub struct SignedTransaction {
     pub public_key: Vec<u8> 
 }
 
+// A coinbase transaction, minting the block reward to the block's miner
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct CoinbaseTransaction {
+    pub height: u64,    // height of the block, making every coinbase unique
+    pub receiver: Address,
+    pub value: u128,
+    pub extra_nonce: u64    // bumped by the miner for a fresh merkle root once the header nonces run out
+}
+
+// Implement the hash function for CoinbaseTransaction
+impl Hashable for CoinbaseTransaction {
+    fn hash(&self) -> H256 {
+        let serialized_transaction: Vec<u8> = bincode::serialize(self).unwrap();
+        ring::digest::digest(&ring::digest::SHA256, &serialized_transaction).into()
+    }
+}
+
 // Implement the hash function for SignedTransaction 
 impl Hashable for SignedTransaction {
     fn hash(&self) -> H256 {
@@ -37,9 +54,7 @@ pub fn sign(t: &Transaction, key: &Ed25519KeyPair) -> Signature {
     let transaction_bytes: Vec<u8> = bincode::serialize(t).unwrap();
 
     // Sign the serialized transaction with the private key
-    let signature = key.sign(&transaction_bytes);
-
-    signature
+    key.sign(&transaction_bytes)
 }
 
 /// Verify digital signature of a transaction, using public key instead of secret key
@@ -52,14 +67,13 @@ pub fn verify(t: &Transaction, public_key: &[u8], signature: &[u8]) -> bool {
     let public_key = signature::UnparsedPublicKey::new(&signature::ED25519, public_key);
 
     // Verify the signature using the public key
-    match public_key.verify(&transaction_bytes, signature) {
-        Ok(_) => true,   // Signature is valid
-        Err(_) => false, // Signature is invalid
-    }    
+    public_key.verify(&transaction_bytes, signature).is_ok()
 }
 
-#[cfg(any(test, test_utilities))]
+#[cfg(any(test, feature = "test-utilities"))]
 pub fn generate_random_transaction() -> Transaction { 
+    use rand::Rng;
+
     // Create a random number generator
     fn generate_random_bytes() -> [u8; 20] {
         let mut rng = rand::thread_rng();
@@ -70,16 +84,18 @@ pub fn generate_random_transaction() -> Transaction {
 
     let mut rng = rand::thread_rng();
 
-    // Generate random values for sender, receiver, and value
+    // Generate random values for sender, receiver, value, and fee
     let account_nonce = rng.gen::<u128>();       
     let receiver = Address::from_public_key_bytes(&generate_random_bytes());     
     let value = rng.gen::<u128>();    
+    let fee = rng.gen::<u128>();
 
     // Create a new Transaction with the generated values
     Transaction {
         account_nonce,
         receiver,
         value,
+        fee,
     }
 }
 
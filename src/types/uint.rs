@@ -0,0 +1,215 @@
+use super::hash::H256;
+
+/// A 256-bit unsigned integer, used for arithmetic on difficulty targets.
+#[derive(Eq, PartialEq, Clone, Copy, Debug, Default)]
+pub struct U256([u64; 4]); // little endian limbs
+
+impl U256 {
+    pub const MAX: U256 = U256([u64::MAX; 4]);
+
+    pub fn from_u64(value: u64) -> Self {
+        U256([value, 0, 0, 0])
+    }
+
+    /// Compute `self * mul / div`, or None if the result does not fit in 256 bits
+    pub fn mul_div(&self, mul: u64, div: u64) -> Option<U256> {
+        assert!(div != 0, "division by zero");
+
+        // Multiply into a 320-bit intermediate
+        let mut product = [0u64; 5];
+        let mut carry: u128 = 0;
+        for (product_limb, limb) in product.iter_mut().zip(self.0.iter()) {
+            let value = *limb as u128 * mul as u128 + carry;
+            *product_limb = value as u64;
+            carry = value >> 64;
+        }
+        product[4] = carry as u64;
+
+        // Divide from the most significant limb down
+        let mut quotient = [0u64; 5];
+        let mut remainder: u128 = 0;
+        for i in (0..5).rev() {
+            let current = (remainder << 64) | product[i] as u128;
+            quotient[i] = (current / div as u128) as u64;
+            remainder = current % div as u128;
+        }
+
+        if quotient[4] != 0 {
+            return None;
+        }
+        Some(U256([quotient[0], quotient[1], quotient[2], quotient[3]]))
+    }
+
+    /// Add two integers, or None on overflow
+    pub fn checked_add(&self, other: &U256) -> Option<U256> {
+        let mut sum = [0u64; 4];
+        let mut carry = false;
+        for (i, limb) in sum.iter_mut().enumerate() {
+            let (value, overflow1) = self.0[i].overflowing_add(other.0[i]);
+            let (value, overflow2) = value.overflowing_add(carry as u64);
+            *limb = value;
+            carry = overflow1 || overflow2;
+        }
+        if carry {
+            return None;
+        }
+        Some(U256(sum))
+    }
+
+    /// Add two integers, saturating at U256::MAX
+    pub fn saturating_add(&self, other: &U256) -> U256 {
+        self.checked_add(other).unwrap_or(U256::MAX)
+    }
+
+    // Subtract modulo 2^256
+    fn wrapping_sub(&self, other: &U256) -> U256 {
+        let mut difference = [0u64; 4];
+        let mut borrow = false;
+        for (i, limb) in difference.iter_mut().enumerate() {
+            let (value, underflow1) = self.0[i].overflowing_sub(other.0[i]);
+            let (value, underflow2) = value.overflowing_sub(borrow as u64);
+            *limb = value;
+            borrow = underflow1 || underflow2;
+        }
+        U256(difference)
+    }
+
+    // Shift left by one bit, returning the bit shifted out
+    fn shl1(&self) -> (U256, bool) {
+        let mut shifted = [0u64; 4];
+        for (i, limb) in shifted.iter_mut().enumerate() {
+            let carry_in = if i == 0 { 0 } else { self.0[i - 1] >> 63 };
+            *limb = (self.0[i] << 1) | carry_in;
+        }
+        (U256(shifted), self.0[3] >> 63 == 1)
+    }
+
+    fn bit(&self, index: usize) -> bool {
+        (self.0[index / 64] >> (index % 64)) & 1 == 1
+    }
+
+    /// Divide by a nonzero integer, rounding down
+    pub fn div(&self, divisor: &U256) -> U256 {
+        assert!(*divisor != U256::default(), "division by zero");
+
+        // Binary long division, one bit of the dividend at a time
+        let mut quotient = U256::default();
+        let mut remainder = U256::default();
+        for index in (0..256).rev() {
+            let (shifted, overflow) = remainder.shl1();
+            remainder = shifted;
+            remainder.0[0] |= self.bit(index) as u64;
+
+            // A bit shifted out means the remainder certainly exceeds the divisor
+            if overflow || remainder >= *divisor {
+                remainder = remainder.wrapping_sub(divisor);
+                quotient.0[index / 64] |= 1 << (index % 64);
+            }
+        }
+        quotient
+    }
+
+    /// Expected number of hashes needed to find a block hash at or below `target`,
+    /// i.e. 2^256 / (target + 1)
+    pub fn work(target: &H256) -> U256 {
+        let target = U256::from(*target);
+        match target.checked_add(&U256::from_u64(1)) {
+            // 2^256 / (target + 1) == (2^256 - target - 1) / (target + 1) + 1
+            Some(divisor) => U256::MAX.wrapping_sub(&target).div(&divisor).saturating_add(&U256::from_u64(1)),
+            None => U256::from_u64(1),    // target is 2^256 - 1
+        }
+    }
+}
+
+impl Ord for U256 {
+    fn cmp(&self, other: &U256) -> std::cmp::Ordering {
+        self.0.iter().rev().cmp(other.0.iter().rev())
+    }
+}
+
+impl PartialOrd for U256 {
+    fn partial_cmp(&self, other: &U256) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl std::convert::From<H256> for U256 {
+    fn from(input: H256) -> U256 {
+        let bytes: [u8; 32] = input.into();
+        let mut limbs = [0u64; 4];
+        for (i, limb) in limbs.iter_mut().enumerate() {
+            let mut buffer = [0u8; 8];
+            buffer.copy_from_slice(&bytes[24 - 8 * i..32 - 8 * i]);
+            *limb = u64::from_be_bytes(buffer);
+        }
+        U256(limbs)
+    }
+}
+
+impl std::convert::From<U256> for H256 {
+    fn from(input: U256) -> H256 {
+        let mut bytes = [0u8; 32];
+        for (i, limb) in input.0.iter().enumerate() {
+            bytes[24 - 8 * i..32 - 8 * i].copy_from_slice(&limb.to_be_bytes());
+        }
+        bytes.into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::hash::generate_random_hash;
+
+    #[test]
+    fn h256_round_trip() {
+        let hash = generate_random_hash();
+        let value: U256 = hash.into();
+        assert_eq!(H256::from(value), hash);
+    }
+
+    #[test]
+    fn ordering_matches_h256() {
+        let a = generate_random_hash();
+        let b = generate_random_hash();
+        assert_eq!(U256::from(a).cmp(&U256::from(b)), a.cmp(&b));
+    }
+
+    #[test]
+    fn mul_div() {
+        let target: U256 = H256::from(hex!("0000100000000000000000000000000000000000000000000000000000000000")).into();
+        let half: H256 = target.mul_div(1, 2).unwrap().into();
+        assert_eq!(half, hex!("0000080000000000000000000000000000000000000000000000000000000000").into());
+        let scaled: H256 = target.mul_div(3, 4).unwrap().into();
+        assert_eq!(scaled, hex!("00000c0000000000000000000000000000000000000000000000000000000000").into());
+        assert_eq!(U256::from_u64(7).mul_div(u64::MAX, u64::MAX), Some(U256::from_u64(7)));
+
+        // The intermediate product may exceed 256 bits as long as the result does not
+        let max: U256 = H256::from([0xff; 32]).into();
+        assert_eq!(max.mul_div(3, 3), Some(max));
+        assert_eq!(max.mul_div(2, 1), None);
+    }
+
+    #[test]
+    fn add_and_div() {
+        let a: U256 = generate_random_hash().into();
+        let b = U256::from_u64(12345);
+        assert_eq!(a.div(&b), a.mul_div(1, 12345).unwrap());
+        assert_eq!(a.div(&a), U256::from_u64(1));
+        assert_eq!(b.div(&a), U256::default());
+        assert_eq!(U256::MAX.div(&U256::MAX.mul_div(1, 2).unwrap()), U256::from_u64(2));
+        assert_eq!(U256::from_u64(u64::MAX).checked_add(&U256::from_u64(1)), Some(U256([0, 1, 0, 0])));
+        assert_eq!(U256::MAX.checked_add(&U256::from_u64(1)), None);
+        assert_eq!(U256::MAX.saturating_add(&U256::from_u64(1)), U256::MAX);
+    }
+
+    #[test]
+    fn work_from_target() {
+        let target: H256 = hex!("0000100000000000000000000000000000000000000000000000000000000000").into();
+        assert_eq!(U256::work(&target), U256::from_u64((1 << 20) - 1));
+        let target: H256 = hex!("7fffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff").into();
+        assert_eq!(U256::work(&target), U256::from_u64(2));
+        assert_eq!(U256::work(&[0xff; 32].into()), U256::from_u64(1));
+        assert_eq!(U256::work(&H256::default()), U256::MAX);
+    }
+}